@@ -1,4 +1,6 @@
-create or replace function html(text) returns text language sql as $$
+drop function if exists html(text);
+-- the csrf token, if any, is sent along with every htmx request of the page
+create or replace function html(text, csrf text default null) returns text language sql as $$
   select format($html$
     <!DOCTYPE html>
     <html>
@@ -10,28 +12,84 @@ create or replace function html(text) returns text language sql as $$
         <script src="htmx.js"></script>
         <script src="sse.js"></script>
       </head>
-      <body>
+      <body%s>
         <header></header>
         <main>%s</main>
         <footer></footer>
       </body>
     </html>
-  $html$, $1);
+  $html$, case when $2 is not null then format(' hx-headers=''%s''', json_build_object('X-CSRF-Token', $2)) else '' end, $1);
 $$;
 
-create or replace function html_index() returns text language sql as $$
+drop function if exists html_index();
+-- events with primary keys refresh just those rows, others reload the whole list
+create or replace function html_index(csrf text default null) returns text language sql as $$
   select html($html$
-    <div hx-ext="sse" sse-connect="/listen/users_event">
-      <div hx-trigger="sse:users_event, revealed" hx-get="/api/users?select=id,username"></div>
-    </div>
-  $html$);
+    <div id="users" hx-trigger="revealed, users_reload" hx-get="/api/users?select=id,username"></div>
+    <script>
+      new EventSource('/listen/users_event').addEventListener('users_event', async (event) => {
+        const users = document.getElementById('users');
+        const tbody = users.querySelector('tbody');
+        const { pks } = JSON.parse(event.data);
+        if (!pks || !tbody) {
+          htmx.trigger(users, 'users_reload');
+          return;
+        }
+        const response = await fetch(`/api/users?select=id,username&id=in.(${pks.map(encodeURIComponent).join(',')})`, { headers: { accept: 'text/html' } });
+        const rows = new DOMParser().parseFromString(await response.text(), 'text/html');
+        for (const pk of pks) {
+          const row = rows.getElementById(pk);
+          const shown = document.getElementById(pk);
+          if (row && shown) {
+            shown.replaceWith(row);
+          } else if (row) {
+            tbody.append(row);
+          } else if (shown) {
+            shown.remove();
+          }
+        }
+      });
+    </script>
+    <p>
+      Download:
+      <a href="/api/users.csv?select=id,username">csv</a>
+      <a href="/api/users.xlsx?select=id,username">xlsx</a>
+      <a href="/api/users.ods?select=id,username">ods</a>
+    </p>
+  $html$, $1);
 $$;
 
+-- one notification per statement with the number of rows changed and their primary keys, or
+-- null past 100 of them, which the page reloads the whole list for
 create or replace function trg_users_event () returns trigger language plpgsql as $$
+declare
+  n bigint;
+  pks json;
 begin
-  perform pg_notify('users_event', '');
+  if tg_op = 'DELETE' then
+    select count(*), case when count(*) <= 100 then json_agg(id) end into n, pks from old_rows;
+  else
+    select count(*), case when count(*) <= 100 then json_agg(id) end into n, pks from new_rows;
+  end if;
+  if n > 0 then
+    perform pg_notify('users_event', json_build_object('count', n, 'pks', pks)::text);
+  end if;
   return null;
 end;
 $$;
 
-create or replace trigger trg_users_event after insert or update or delete on users execute function trg_users_event();
+-- transition tables take a trigger per event
+drop trigger if exists trg_users_event on users;
+create or replace trigger trg_users_event_insert after insert on users referencing new table as new_rows for each statement execute function trg_users_event();
+create or replace trigger trg_users_event_update after update on users referencing new table as new_rows for each statement execute function trg_users_event();
+create or replace trigger trg_users_event_delete after delete on users referencing old table as old_rows for each statement execute function trg_users_event();
+
+select tankard_watch('users');
+select tankard_audit_attach('users');
+select tankard_allow_listen('users_event');
+
+-- anonymous requests run as `tankard_anon` unless `TANKARD_ANON_ROLE` names another role, the
+-- index page lists users and listens to their changes as it
+do $$ begin create role tankard_anon nologin; exception when duplicate_object or unique_violation then null; end $$;
+grant tankard_anon to current_user;
+grant select (id, username) on users to tankard_anon;
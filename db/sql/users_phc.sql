@@ -0,0 +1,5 @@
+-- Moves `users` from the separate-salt layout to PHC strings in `passhash`.
+-- Hashes are taken to be hex argon2id at the default costs, pass the costs they were made with
+-- to `tankard_password_phc` otherwise. Rows with an empty salt already hold a PHC string.
+update users set passhash = tankard_password_phc(salt, passhash) where salt <> '';
+alter table users drop column salt;
@@ -0,0 +1,97 @@
+use pgrx::prelude::*;
+
+extension_sql!(
+    r#"
+create table tankard_api_keys (
+  -- sha256 of the key
+  id bytea primary key,
+  name text not null unique,
+  -- requests made with the key act as this user, if any
+  user_id uuid,
+  -- like `read:users`, `write:orders` or `rpc:*`
+  scopes text[] not null default '{}',
+  added timestamptz not null default now(),
+  expires timestamptz,
+  last_used timestamptz
+);
+revoke all on tankard_api_keys from public;
+
+-- returns the key, which is not stored and cannot be shown again
+create function tankard_api_key_create(name text, scopes text[], expires timestamptz default null, user_id uuid default null) returns text language sql as $$
+  with key as (
+    select 'tk_' || replace(gen_random_uuid()::text || gen_random_uuid()::text, '-', '') as key
+  ), created as (
+    insert into tankard_api_keys (id, name, user_id, scopes, expires) select sha256(convert_to(key, 'utf8')), $1, $4, $2, $3 from key
+  )
+  select key from key;
+$$;
+
+-- the name, user and scopes of a valid key, marking it used; no rows for unknown and expired keys
+create function tankard_api_key_use(key text) returns table (name text, user_id uuid, scopes text[]) language sql as $$
+  update tankard_api_keys set last_used = now()
+  where id = sha256(convert_to($1, 'utf8')) and (expires is null or expires > now())
+  returning name, user_id, scopes;
+$$;
+
+-- whether there was a key of that name
+create function tankard_api_key_revoke(name text) returns boolean language sql as $$
+  with revoked as (
+    delete from tankard_api_keys where tankard_api_keys.name = $1 returning 1
+  )
+  select count(*) > 0 from revoked;
+$$;
+"#,
+    name = "api_keys",
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+pub(crate) mod tests {
+    use pgrx::prelude::*;
+
+    #[pg_test]
+    fn tankard_api_key_lifecycle() -> Result<(), spi::Error> {
+        let key = Spi::get_one::<String>(
+            "select tankard_api_key_create('script', '{read:users}');",
+        )?
+        .unwrap();
+
+        assert!(key.starts_with("tk_"));
+        assert_eq!(
+            Spi::get_one::<Vec<String>>(&format!(
+                "select scopes from tankard_api_key_use('{key}');"
+            ))?,
+            Some(vec!["read:users".to_string()])
+        );
+        assert_eq!(
+            Spi::get_one::<bool>("select last_used is not null from tankard_api_keys;")?,
+            Some(true)
+        );
+
+        assert_eq!(
+            Spi::get_one::<bool>("select tankard_api_key_revoke('script');")?,
+            Some(true)
+        );
+        assert_eq!(
+            Spi::get_one::<String>(&format!("select name from tankard_api_key_use('{key}');"))?,
+            None
+        );
+
+        Ok(())
+    }
+
+    #[pg_test]
+    fn tankard_api_key_expired() -> Result<(), spi::Error> {
+        let key = Spi::get_one::<String>(
+            "select tankard_api_key_create('script', '{}', now() - interval '1 second');",
+        )?
+        .unwrap();
+
+        assert_eq!(
+            Spi::get_one::<String>(&format!("select name from tankard_api_key_use('{key}');"))?,
+            None
+        );
+
+        Ok(())
+    }
+}
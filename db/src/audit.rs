@@ -0,0 +1,101 @@
+use pgrx::prelude::*;
+
+extension_sql!(
+    r#"
+create table tankard_audit (
+  id bigserial primary key,
+  table_name text not null,
+  pk jsonb not null,
+  op text not null,
+  old jsonb,
+  new jsonb,
+  -- `tankard.user_id` or `tankard.api_key` of the request, the role outside of one
+  actor text not null,
+  -- `x-request-id` header of the request
+  request_id text,
+  at timestamptz not null default now()
+);
+revoke all on tankard_audit from public;
+
+create index on tankard_audit (table_name, at);
+create index on tankard_audit using gin (pk);
+
+-- security definer, so roles writing audited tables need no grants on `tankard_audit`
+create function tankard_audit_record() returns trigger language plpgsql security definer set search_path from current as $$
+declare
+  old_row jsonb := case when tg_op in ('UPDATE', 'DELETE') then to_jsonb(old) end;
+  new_row jsonb := case when tg_op in ('INSERT', 'UPDATE') then to_jsonb(new) end;
+  pk jsonb;
+begin
+  select jsonb_object_agg(key, coalesce(new_row, old_row) -> key) into pk from unnest(tg_argv) key;
+  insert into tankard_audit (table_name, pk, op, old, new, actor, request_id)
+  values (
+    tg_table_name,
+    pk,
+    lower(tg_op),
+    old_row,
+    new_row,
+    coalesce(
+      nullif(current_setting('tankard.user_id', true), ''),
+      'api_key:' || nullif(current_setting('tankard.api_key', true), ''),
+      nullif(current_setting('role'), 'none'),
+      session_user
+    ),
+    nullif(current_setting('tankard.headers', true), '')::jsonb ->> 'x-request-id'
+  );
+  return null;
+end;
+$$;
+
+create function tankard_audit_attach(tbl regclass) returns void language plpgsql as $$
+declare
+  pk text;
+begin
+  select string_agg(quote_literal(a.attname), ',' order by array_position(i.indkey::int2[], a.attnum)) into pk
+  from pg_index i
+  join pg_attribute a on a.attrelid = i.indrelid and a.attnum = any(i.indkey)
+  where i.indrelid = tbl and i.indisprimary;
+
+  if pk is null then
+    raise exception '% has no primary key', tbl;
+  end if;
+
+  execute format(
+    'create or replace trigger tankard_audit after insert or update or delete on %s for each row execute function tankard_audit_record(%s)',
+    tbl, pk
+  );
+end;
+$$;
+
+create function tankard_audit_detach(tbl regclass) returns void language plpgsql as $$
+begin
+  execute format('drop trigger if exists tankard_audit on %s', tbl);
+end;
+$$;
+"#,
+    name = "audit",
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+pub(crate) mod tests {
+    use pgrx::prelude::*;
+
+    #[pg_test]
+    fn tankard_audit_record() -> Result<(), spi::Error> {
+        Spi::run(include_str!("../sql/users.sql"))?;
+        Spi::run("select tankard_audit_attach('users');")?;
+        Spi::run("select set_config('tankard.user_id', '00000000-0000-0000-0000-000000000009', true), set_config('tankard.headers', '{\"x-request-id\": \"one\"}', true);")?;
+        Spi::run("insert into users (id, username, salt, passhash) values ('00000000-0000-0000-0000-000000000001', 'one', '', '');")?;
+        Spi::run("update users set username = 'two';")?;
+
+        assert_eq!(
+            Spi::get_one::<String>(
+                "select string_agg(op || ' ' || (pk ->> 'id') || ' ' || actor || ' ' || request_id || ' ' || (new ->> 'username'), ',' order by id) from tankard_audit;"
+            )?,
+            Some("insert 00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000009 one one,update 00000000-0000-0000-0000-000000000001 00000000-0000-0000-0000-000000000009 one two".to_string())
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+use pgrx::prelude::*;
+
+extension_sql!(
+    r#"
+create table tankard_outbox (
+  id bigserial primary key,
+  -- ids are taken on insert rather than commit, readers go by transaction behind `pg_snapshot_xmin`
+  xid xid8 not null default pg_current_xact_id(),
+  at timestamptz not null default now(),
+  payload jsonb not null
+);
+revoke all on tankard_outbox from public;
+
+create index on tankard_outbox (xid, id);
+
+-- security definer, so roles writing watched tables need no grants on `tankard_outbox`
+create function tankard_change_notify() returns trigger language plpgsql security definer set search_path from current as $$
+declare
+  old_row jsonb := case when tg_op in ('UPDATE', 'DELETE') then to_jsonb(old) end;
+  new_row jsonb := case when tg_op in ('INSERT', 'UPDATE') then to_jsonb(new) end;
+  pk jsonb;
+  change tankard_outbox;
+  payload text;
+begin
+  select jsonb_object_agg(key, coalesce(new_row, old_row) -> key) into pk from unnest(tg_argv) key;
+  insert into tankard_outbox (payload)
+  values (jsonb_build_object('op', lower(tg_op), 'table', tg_table_name, 'pk', pk, 'old', old_row, 'new', new_row))
+  returning * into change;
+  payload := (change.payload || jsonb_build_object('id', change.id))::text;
+  -- notify payloads must be shorter than 8000 bytes, listeners read the rest from the outbox instead
+  if octet_length(payload) >= 8000 then
+    payload := jsonb_build_object('id', change.id, 'op', lower(tg_op), 'table', tg_table_name, 'pk', pk)::text;
+  end if;
+  perform pg_notify('tankard_changes', payload);
+  return null;
+end;
+$$;
+
+-- whether the current role could see `image`, a row of `tbl` as `to_jsonb` has it, by grants and
+-- row-level security, for rows no longer there to select
+create function tankard_visible(tbl regclass, image jsonb) returns boolean language plpgsql stable as $$
+declare
+  permissive text;
+  restrictive text;
+  visible boolean;
+begin
+  if image is null or not has_any_column_privilege(tbl, 'select') then
+    return false;
+  end if;
+  if (select rolsuper or rolbypassrls from pg_roles where rolname = current_user)
+    or not exists (
+      select from pg_class c
+      where c.oid = tbl and c.relrowsecurity
+        and (c.relforcerowsecurity or not pg_has_role(current_user, c.relowner, 'usage'))
+    ) then
+    return true;
+  end if;
+
+  select
+    string_agg('(' || pg_get_expr(p.polqual, p.polrelid) || ')', ' or ') filter (where p.polpermissive),
+    string_agg('(' || pg_get_expr(p.polqual, p.polrelid) || ')', ' and ') filter (where not p.polpermissive)
+  into permissive, restrictive
+  from pg_policy p
+  where p.polrelid = tbl and p.polcmd in ('r', '*') and p.polqual is not null
+    and exists (
+      select from unnest(p.polroles) r
+      where case when r = 0 then true else pg_has_role(current_user, r, 'member') end
+    );
+  -- like row-level security, nothing is visible without a permissive policy
+  if permissive is null then
+    return false;
+  end if;
+
+  execute format(
+    'select exists (select from jsonb_populate_record(null::%s, $1) %I where (%s) and %s)',
+    tbl, (select relname from pg_class where oid = tbl), permissive, coalesce(restrictive, 'true')
+  ) into visible using image;
+  return visible;
+end;
+$$;
+
+-- keeps `tankard.outbox_retention` worth of changes, one day unless set
+create function tankard_outbox_prune() returns bigint language sql as $$
+  with pruned as (
+    delete from tankard_outbox
+    where at < now() - coalesce(nullif(current_setting('tankard.outbox_retention', true), ''), '1 day')::interval
+    returning 1
+  )
+  select count(*) from pruned;
+$$;
+
+create function tankard_watch(tbl regclass) returns void language plpgsql as $$
+declare
+  pk text;
+begin
+  select string_agg(quote_literal(a.attname), ',' order by array_position(i.indkey::int2[], a.attnum)) into pk
+  from pg_index i
+  join pg_attribute a on a.attrelid = i.indrelid and a.attnum = any(i.indkey)
+  where i.indrelid = tbl and i.indisprimary;
+
+  if pk is null then
+    raise exception '% has no primary key', tbl;
+  end if;
+
+  execute format(
+    'create or replace trigger tankard_changes after insert or update or delete on %s for each row execute function tankard_change_notify(%s)',
+    tbl, pk
+  );
+end;
+$$;
+
+create function tankard_unwatch(tbl regclass) returns void language plpgsql as $$
+begin
+  execute format('drop trigger if exists tankard_changes on %s', tbl);
+end;
+$$;
+"#,
+    name = "changes",
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+pub(crate) mod tests {
+    use pgrx::prelude::*;
+
+    #[pg_test]
+    fn tankard_watch_users() -> Result<(), spi::Error> {
+        Spi::run(include_str!("../sql/users.sql"))?;
+        Spi::run("select tankard_watch('users');")?;
+
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "select count(*) from pg_trigger where tgrelid = 'users'::regclass and tgname = 'tankard_changes';"
+            )?,
+            Some(1)
+        );
+
+        Spi::run("select tankard_unwatch('users');")?;
+
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "select count(*) from pg_trigger where tgrelid = 'users'::regclass and tgname = 'tankard_changes';"
+            )?,
+            Some(0)
+        );
+
+        Ok(())
+    }
+
+    #[pg_test]
+    fn tankard_outbox_users() -> Result<(), spi::Error> {
+        Spi::run(include_str!("../sql/users.sql"))?;
+        Spi::run("select tankard_watch('users');")?;
+        Spi::run("insert into users (username, salt, passhash, email) values ('one', '', '', 'foo');")?;
+        Spi::run("update users set email = 'bar';")?;
+
+        assert_eq!(
+            Spi::get_one::<Vec<String>>(
+                "select array_agg(payload ->> 'op' order by id) from tankard_outbox where payload ->> 'table' = 'users';"
+            )?,
+            Some(vec!["insert".to_string(), "update".to_string()])
+        );
+
+        Spi::run("update tankard_outbox set at = now() - interval '2 days';")?;
+        Spi::run("set tankard.outbox_retention = '1 day';")?;
+
+        assert_eq!(
+            Spi::get_one::<i64>("select tankard_outbox_prune();")?,
+            Some(2)
+        );
+
+        Ok(())
+    }
+
+    #[pg_test]
+    fn tankard_visible_users() -> Result<(), spi::Error> {
+        Spi::run(include_str!("../sql/users.sql"))?;
+        Spi::run("create role tankard_reader; grant select on users to tankard_reader;")?;
+        Spi::run("alter table users enable row level security; create policy own on users using (username = 'one');")?;
+        Spi::run("set local role tankard_reader;")?;
+
+        assert_eq!(
+            Spi::get_one::<bool>(r#"select tankard_visible('users', '{"username": "one"}');"#)?,
+            Some(true)
+        );
+        assert_eq!(
+            Spi::get_one::<bool>(r#"select tankard_visible('users', '{"username": "two"}');"#)?,
+            Some(false)
+        );
+
+        Ok(())
+    }
+
+    #[pg_test(error = "no_key has no primary key")]
+    fn tankard_watch_no_key() -> Result<(), spi::Error> {
+        Spi::run("create table no_key (value text);")?;
+        Spi::run("select tankard_watch('no_key');")
+    }
+}
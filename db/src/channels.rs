@@ -0,0 +1,72 @@
+use pgrx::prelude::*;
+
+extension_sql!(
+    r#"
+create table tankard_channels (
+  name text primary key,
+  -- members of `role` may listen, anyone when null
+  role regrole
+);
+grant select on tankard_channels to public;
+
+create function tankard_allow_listen(channel text, role regrole default null) returns void language sql as $$
+  insert into tankard_channels (name, role) values (channel, role)
+  on conflict (name) do update set role = excluded.role;
+$$;
+
+create function tankard_deny_listen(channel text) returns void language sql as $$
+  delete from tankard_channels where name = channel;
+$$;
+
+-- null for channels that are not registered
+create function tankard_can_listen(channel text) returns boolean language sql stable as $$
+  select role is null or pg_has_role(current_user, role, 'member') from tankard_channels where name = channel;
+$$;
+"#,
+    name = "channels",
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+pub(crate) mod tests {
+    use pgrx::prelude::*;
+
+    #[pg_test]
+    fn tankard_can_listen_registered() -> Result<(), spi::Error> {
+        assert_eq!(
+            Spi::get_one::<bool>("select tankard_can_listen('users_event');")?,
+            None
+        );
+
+        Spi::run("select tankard_allow_listen('users_event');")?;
+
+        assert_eq!(
+            Spi::get_one::<bool>("select tankard_can_listen('users_event');")?,
+            Some(true)
+        );
+
+        Spi::run("select tankard_deny_listen('users_event');")?;
+
+        assert_eq!(
+            Spi::get_one::<bool>("select tankard_can_listen('users_event');")?,
+            None
+        );
+
+        Ok(())
+    }
+
+    #[pg_test]
+    fn tankard_can_listen_role() -> Result<(), spi::Error> {
+        Spi::run("create role tankard_listener;")?;
+        Spi::run("create role tankard_outsider;")?;
+        Spi::run("select tankard_allow_listen('secret', 'tankard_listener');")?;
+        Spi::run("set role tankard_outsider;")?;
+
+        assert_eq!(
+            Spi::get_one::<bool>("select tankard_can_listen('secret');")?,
+            Some(false)
+        );
+
+        Ok(())
+    }
+}
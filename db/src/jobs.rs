@@ -0,0 +1,176 @@
+use pgrx::prelude::*;
+
+extension_sql!(
+    r#"
+create table tankard_jobs (
+  id bigserial primary key,
+  queue text not null default 'default',
+  kind text not null,
+  payload jsonb not null default '{}',
+  status text not null default 'pending' check (status in ('pending', 'running', 'done', 'failed')),
+  attempts int not null default 0,
+  max_attempts int not null default 5,
+  run_at timestamptz not null default now(),
+  locked_at timestamptz,
+  error text,
+  added timestamptz not null default now(),
+  updated timestamptz not null default now(),
+  -- jobs are enqueued once per key, when every instance enqueues the same one
+  key text unique
+);
+revoke all on tankard_jobs from public;
+
+create index on tankard_jobs (queue, run_at) where status = 'pending';
+
+-- null when a job of `key` was enqueued before
+create function tankard_enqueue(kind text, payload jsonb default '{}', run_at timestamptz default now(), queue text default 'default', max_attempts int default 5, key text default null) returns bigint language sql as $$
+  select pg_notify('tankard_jobs', $4);
+  insert into tankard_jobs (kind, payload, run_at, queue, max_attempts, key) values ($1, $2, $3, $4, $5, $6) on conflict (key) do nothing returning id;
+$$;
+
+-- claims the next due job of one of `kinds`, along with running jobs whose worker went away,
+-- those without a `tankard_heartbeat` for `timeout`, failing the ones out of attempts instead
+create function tankard_dequeue(queue text, kinds text[], timeout interval default '5 minutes') returns setof tankard_jobs language sql as $$
+  update tankard_jobs
+  set status = 'failed', locked_at = null, error = 'worker went away', updated = now()
+  where tankard_jobs.queue = $1 and kind = any($2) and status = 'running' and locked_at < now() - $3
+    and attempts >= max_attempts;
+  update tankard_jobs j
+  set status = 'running', attempts = j.attempts + 1, locked_at = now(), updated = now()
+  where j.id = (
+    select id from tankard_jobs
+    where tankard_jobs.queue = $1 and kind = any($2) and (
+      (status = 'pending' and run_at <= now()) or (status = 'running' and locked_at < now() - $3)
+    )
+    order by run_at, id
+    for update skip locked
+    limit 1
+  )
+  returning j.*;
+$$;
+
+-- keeps a running job claimed, workers call it more often than the `timeout` of `tankard_dequeue`
+create function tankard_heartbeat(job bigint) returns void language sql as $$
+  update tankard_jobs set locked_at = now(), updated = now() where id = $1 and status = 'running';
+$$;
+
+create function tankard_complete(job bigint) returns void language sql as $$
+  update tankard_jobs set status = 'done', locked_at = null, error = null, updated = now() where id = $1;
+$$;
+
+-- retries after `retry_in`, or exponential backoff, until the attempts run out
+create function tankard_fail(job bigint, error text, retry_in interval default null) returns void language sql as $$
+  update tankard_jobs
+  set
+    status = case when attempts < max_attempts then 'pending' else 'failed' end,
+    run_at = case when attempts < max_attempts then now() + coalesce($3, interval '1 second' * power(2, attempts)) else run_at end,
+    locked_at = null,
+    error = $2,
+    updated = now()
+  where id = $1;
+$$;
+"#,
+    name = "jobs",
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+pub(crate) mod tests {
+    use pgrx::prelude::*;
+
+    #[pg_test]
+    fn tankard_jobs_lifecycle() -> Result<(), spi::Error> {
+        let id =
+            Spi::get_one::<i64>("select tankard_enqueue('email', '{\"to\": \"foo\"}');")?.unwrap();
+
+        assert_eq!(
+            Spi::get_one::<i64>("select (select id from tankard_dequeue('default', '{export}'));")?,
+            None
+        );
+        assert_eq!(
+            Spi::get_one::<i64>("select (select id from tankard_dequeue('default', '{email}'));")?,
+            Some(id)
+        );
+        assert_eq!(
+            Spi::get_one::<i64>("select (select id from tankard_dequeue('default', '{email}'));")?,
+            None
+        );
+
+        Spi::run(&format!("select tankard_fail({id}, 'boom', '0 seconds');"))?;
+
+        assert_eq!(
+            Spi::get_one::<i64>("select (select id from tankard_dequeue('default', '{email}'));")?,
+            Some(id)
+        );
+
+        Spi::run(&format!("select tankard_complete({id});"))?;
+
+        assert_eq!(
+            Spi::get_one::<String>("select status || ' ' || attempts from tankard_jobs;")?,
+            Some("done 2".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[pg_test]
+    fn tankard_jobs_exhausted() -> Result<(), spi::Error> {
+        Spi::run("select tankard_enqueue('email', max_attempts => 1);")?;
+        Spi::run("select tankard_fail(id, 'boom') from tankard_dequeue('default', '{email}');")?;
+
+        assert_eq!(
+            Spi::get_one::<String>("select status from tankard_jobs;")?,
+            Some("failed".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[pg_test]
+    fn tankard_jobs_stale() -> Result<(), spi::Error> {
+        let id = Spi::get_one::<i64>("select tankard_enqueue('email', max_attempts => 2);")?.unwrap();
+        Spi::run("select tankard_dequeue('default', '{email}');")?;
+        Spi::run("update tankard_jobs set locked_at = now() - interval '1 hour';")?;
+        Spi::run(&format!("select tankard_heartbeat({id});"))?;
+
+        assert_eq!(
+            Spi::get_one::<i64>("select (select id from tankard_dequeue('default', '{email}'));")?,
+            None
+        );
+
+        Spi::run("update tankard_jobs set locked_at = now() - interval '1 hour';")?;
+
+        assert_eq!(
+            Spi::get_one::<i64>("select (select id from tankard_dequeue('default', '{email}'));")?,
+            Some(id)
+        );
+
+        Spi::run("update tankard_jobs set locked_at = now() - interval '1 hour';")?;
+
+        assert_eq!(
+            Spi::get_one::<i64>("select (select id from tankard_dequeue('default', '{email}'));")?,
+            None
+        );
+        assert_eq!(
+            Spi::get_one::<String>("select status || ' ' || attempts from tankard_jobs;")?,
+            Some("failed 2".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[pg_test]
+    fn tankard_jobs_key() -> Result<(), spi::Error> {
+        assert!(Spi::get_one::<i64>("select tankard_enqueue('email', key => 'one');")?.is_some());
+        assert_eq!(
+            Spi::get_one::<i64>("select tankard_enqueue('email', key => 'one');")?,
+            None
+        );
+        assert_eq!(
+            Spi::get_one::<i64>("select count(*) from tankard_jobs;")?,
+            Some(1)
+        );
+
+        Ok(())
+    }
+}
@@ -1,6 +1,15 @@
 ::pgrx::pg_module_magic!();
 
+mod api_keys;
+mod audit;
+mod changes;
+mod channels;
 mod html;
+mod jobs;
+mod passwords;
+mod rate_limits;
+mod sessions;
+mod webhooks;
 
 /// This module is required by `cargo pgrx test` invocations.
 /// It must be visible at the root of your extension crate.
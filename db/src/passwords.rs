@@ -0,0 +1,164 @@
+use argon2::{
+    password_hash::{Output, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use pgrx::prelude::*;
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+const SALT_LEN: usize = 16;
+
+fn argon2(m_cost: i32, t_cost: i32, p_cost: i32) -> Result<Argon2<'static>, Error> {
+    let params = Params::new(
+        m_cost.try_into()?,
+        t_cost.try_into()?,
+        p_cost.try_into()?,
+        None,
+    )?;
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+}
+
+/// Hash `password` with argon2id into a PHC string, with a random salt from Postgres.
+///
+/// The costs default to the OWASP minimum: 19 MiB of memory, 2 iterations and 1 lane.
+#[pg_extern]
+fn tankard_hash_password(
+    password: &str,
+    m_cost: default!(i32, 19456),
+    t_cost: default!(i32, 2),
+    p_cost: default!(i32, 1),
+) -> Result<String, Error> {
+    let mut salt = [0u8; SALT_LEN];
+    if !unsafe { pg_sys::pg_strong_random(salt.as_mut_ptr().cast(), SALT_LEN) } {
+        error!("could not generate a random salt");
+    }
+    Ok(argon2(m_cost, t_cost, p_cost)?
+        .hash_password(password.as_bytes(), &SaltString::encode_b64(&salt)?)?
+        .to_string())
+}
+
+/// Whether `password` matches the PHC string `hash`, with the costs it was hashed with.
+#[pg_extern(immutable, parallel_safe)]
+fn tankard_verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .and_then(|hash| Argon2::default().verify_password(password.as_bytes(), &hash))
+        .is_ok()
+}
+
+/// Whether `hash` is not an argon2id PHC string of the given costs, so it is hashed again on the
+/// next successful login.
+#[pg_extern(immutable, parallel_safe)]
+fn tankard_password_needs_rehash(
+    hash: &str,
+    m_cost: default!(i32, 19456),
+    t_cost: default!(i32, 2),
+    p_cost: default!(i32, 1),
+) -> bool {
+    let Ok(hash) = PasswordHash::new(hash) else {
+        return true;
+    };
+    hash.algorithm != argon2::ARGON2ID_IDENT
+        || hash.version != Some(Version::V0x13.into())
+        || !Params::try_from(&hash).is_ok_and(|params| {
+            (params.m_cost(), params.t_cost(), params.p_cost())
+                == (m_cost as u32, t_cost as u32, p_cost as u32)
+        })
+}
+
+/// PHC string of the separate-salt layout, where `salt` holds the salt as text and `passhash` the
+/// hex of its argon2id hash with the given costs.
+#[pg_extern(immutable, parallel_safe)]
+fn tankard_password_phc(
+    salt: &str,
+    passhash: &str,
+    m_cost: default!(i32, 19456),
+    t_cost: default!(i32, 2),
+    p_cost: default!(i32, 1),
+) -> Result<String, Error> {
+    let output = hex::decode(passhash)?;
+    let phc = format!(
+        "$argon2id$v=19$m={m_cost},t={t_cost},p={p_cost}${}${}",
+        SaltString::encode_b64(salt.as_bytes())?.as_str(),
+        Output::new(&output)?,
+    );
+    PasswordHash::new(&phc)?;
+    Ok(phc)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+pub(crate) mod tests {
+    use pgrx::prelude::*;
+
+    #[pg_test]
+    fn tankard_password_roundtrip() -> Result<(), spi::Error> {
+        let hash = Spi::get_one::<String>(
+            "select tankard_hash_password('hunter2', m_cost => 1024, t_cost => 1);",
+        )?
+        .unwrap();
+
+        assert!(hash.starts_with("$argon2id$v=19$m=1024,t=1,p=1$"));
+        assert_eq!(
+            Spi::get_one_with_args::<bool>(
+                "select tankard_verify_password('hunter2', $1);",
+                vec![(PgBuiltInOids::TEXTOID.oid(), hash.clone().into_datum())]
+            )?,
+            Some(true)
+        );
+        assert_eq!(
+            Spi::get_one_with_args::<bool>(
+                "select tankard_verify_password('hunter3', $1);",
+                vec![(PgBuiltInOids::TEXTOID.oid(), hash.clone().into_datum())]
+            )?,
+            Some(false)
+        );
+        assert_eq!(
+            Spi::get_one_with_args::<bool>(
+                "select tankard_password_needs_rehash($1);",
+                vec![(PgBuiltInOids::TEXTOID.oid(), hash.into_datum())]
+            )?,
+            Some(true)
+        );
+        assert_eq!(
+            Spi::get_one::<bool>("select tankard_verify_password('hunter2', 'nope');")?,
+            Some(false)
+        );
+
+        Ok(())
+    }
+
+    #[pg_test]
+    fn tankard_password_migrated() -> Result<(), spi::Error> {
+        // reference test vector of argon2id: "password" salted with "somesalt", m=64 MiB, t=2, p=1
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "select tankard_verify_password('password', tankard_password_phc('somesalt', '09316115d5cf24ed5a15a31a3ba326e5cf32edc24702987c02b6566f61913cf7', 65536, 2, 1));",
+            )?,
+            Some(true)
+        );
+
+        Ok(())
+    }
+
+    #[pg_test]
+    fn users_phc() -> Result<(), spi::Error> {
+        Spi::run(include_str!("../sql/users.sql"))?;
+        Spi::run("insert into users (username, salt, passhash) values ('one', 'somesalt', '09316115d5cf24ed5a15a31a3ba326e5cf32edc24702987c02b6566f61913cf7'), ('two', '', tankard_hash_password('hunter2'));")?;
+        Spi::run(include_str!("../sql/users_phc.sql"))?;
+
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "select bool_and(passhash like '$argon2id$%') from users;"
+            )?,
+            Some(true)
+        );
+        assert_eq!(
+            Spi::get_one::<bool>(
+                "select tankard_verify_password('hunter2', passhash) from users where username = 'two';"
+            )?,
+            Some(true)
+        );
+
+        Ok(())
+    }
+}
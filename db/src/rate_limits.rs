@@ -0,0 +1,55 @@
+use pgrx::prelude::*;
+
+extension_sql!(
+    r#"
+-- token buckets shared by tankard instances, losing them on a crash only resets the limits
+create unlogged table tankard_rate_limits (
+  id text primary key,
+  tokens float8 not null,
+  updated timestamptz not null
+);
+revoke all on tankard_rate_limits from public;
+
+-- takes a token from the bucket, returns 0 when there was one or else the seconds until there is
+create function tankard_rate_limit_take(bucket text, capacity float8, per_second float8) returns float8 language plpgsql as $$
+declare
+  available float8;
+begin
+  insert into tankard_rate_limits (id, tokens, updated) values (bucket, capacity, clock_timestamp())
+  on conflict (id) do nothing;
+  select least(capacity, tokens + extract(epoch from clock_timestamp() - updated) * per_second) into available
+  from tankard_rate_limits where id = bucket for update;
+  update tankard_rate_limits
+  set tokens = case when available >= 1 then available - 1 else available end, updated = clock_timestamp()
+  where id = bucket;
+  if available >= 1 then
+    return 0;
+  end if;
+  return (1 - available) / per_second;
+end
+$$;
+
+-- full buckets are as good as none
+create function tankard_rate_limit_prune(older_than interval default '1 day') returns void language sql as $$
+  delete from tankard_rate_limits where updated < now() - older_than;
+$$;
+"#,
+    name = "rate_limits",
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+pub(crate) mod tests {
+    use pgrx::prelude::*;
+
+    #[pg_test]
+    fn tankard_rate_limit_take() -> Result<(), spi::Error> {
+        let take = || Spi::get_one::<f64>("select tankard_rate_limit_take('one', 2, 0.5);");
+
+        assert_eq!(take()?, Some(0.0));
+        assert_eq!(take()?, Some(0.0));
+        assert!(take()?.is_some_and(|wait| wait > 1.9 && wait <= 2.0));
+
+        Ok(())
+    }
+}
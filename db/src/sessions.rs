@@ -0,0 +1,80 @@
+use pgrx::prelude::*;
+
+extension_sql!(
+    r#"
+create table tankard_sessions (
+  -- sha256 of the token in the session cookie
+  id bytea primary key,
+  user_id uuid not null,
+  added timestamptz not null default now(),
+  expires timestamptz not null
+);
+revoke all on tankard_sessions from public;
+
+create index on tankard_sessions (expires);
+
+-- returns the token of a new session, clearing out expired ones
+create function tankard_session_start(user_id uuid, ttl interval default '7 days') returns text language sql as $$
+  delete from tankard_sessions where expires < now();
+  with token as (
+    select replace(gen_random_uuid()::text || gen_random_uuid()::text, '-', '') as token
+  ), session as (
+    insert into tankard_sessions (id, user_id, expires) select sha256(convert_to(token, 'utf8')), $1, now() + $2 from token
+  )
+  select token from token;
+$$;
+
+-- null for unknown and expired tokens
+create function tankard_session_user(token text) returns uuid language sql stable as $$
+  select user_id from tankard_sessions where id = sha256(convert_to($1, 'utf8')) and expires > now();
+$$;
+
+create function tankard_session_end(token text) returns void language sql as $$
+  delete from tankard_sessions where id = sha256(convert_to($1, 'utf8'));
+$$;
+"#,
+    name = "sessions",
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+pub(crate) mod tests {
+    use pgrx::prelude::*;
+
+    #[pg_test]
+    fn tankard_session_lifecycle() -> Result<(), spi::Error> {
+        let user = "00000000-0000-0000-0000-000000000001";
+        let token =
+            Spi::get_one::<String>(&format!("select tankard_session_start('{user}');"))?.unwrap();
+
+        assert_eq!(token.len(), 64);
+        assert_eq!(
+            Spi::get_one::<String>(&format!("select tankard_session_user('{token}')::text;"))?,
+            Some(user.to_string())
+        );
+
+        Spi::run(&format!("select tankard_session_end('{token}');"))?;
+
+        assert_eq!(
+            Spi::get_one::<String>(&format!("select tankard_session_user('{token}')::text;"))?,
+            None
+        );
+
+        Ok(())
+    }
+
+    #[pg_test]
+    fn tankard_session_expired() -> Result<(), spi::Error> {
+        let token = Spi::get_one::<String>(
+            "select tankard_session_start('00000000-0000-0000-0000-000000000001', '-1 second');",
+        )?
+        .unwrap();
+
+        assert_eq!(
+            Spi::get_one::<String>(&format!("select tankard_session_user('{token}')::text;"))?,
+            None
+        );
+
+        Ok(())
+    }
+}
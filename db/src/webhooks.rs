@@ -0,0 +1,36 @@
+use pgrx::prelude::*;
+
+extension_sql!(
+    r#"
+create table tankard_webhooks (
+  id bigserial primary key,
+  table_name text not null,
+  events text[] not null default '{insert,update,delete}',
+  -- query string like `/api/:table` takes, e.g. `select=id,email&email=neq.`
+  filter text not null default '',
+  url text not null,
+  -- key of the `X-Tankard-Signature` HMAC-SHA256
+  secret text not null
+);
+revoke all on tankard_webhooks from public;
+
+create table tankard_webhook_dead_letters (
+  id bigserial primary key,
+  webhook bigint not null references tankard_webhooks on delete cascade,
+  payload jsonb not null,
+  error text not null,
+  attempts int not null,
+  at timestamptz not null default now()
+);
+revoke all on tankard_webhook_dead_letters from public;
+
+-- the last outbox change deliveries were queued for, changes pruned before they are reached are not
+create table tankard_webhook_cursor (
+  xid xid8 not null,
+  id bigint not null
+);
+revoke all on tankard_webhook_cursor from public;
+insert into tankard_webhook_cursor values ('0', 0);
+"#,
+    name = "webhooks",
+);
@@ -0,0 +1,19 @@
+-- columns the current role may select, by table
+select
+    table_name::text,
+    coalesce(
+        jsonb_agg(column_name) filter (
+            where has_column_privilege(format('%I.%I', table_schema, table_name), column_name, 'SELECT')
+        ),
+        '[]'
+    ) as columns
+from information_schema.columns
+where table_schema = 'public'
+    -- tables of extensions, `tankard_` ones included, are not served
+    and not exists (
+        select from pg_depend
+        where classid = 'pg_class'::regclass
+            and objid = format('%I.%I', table_schema, table_name)::regclass
+            and deptype = 'e'
+    )
+group by table_name;
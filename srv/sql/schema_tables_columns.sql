@@ -1,6 +1,23 @@
 select
     table_name::text,
-    jsonb_agg(jsonb_build_object('column_name', column_name, 'data_type', data_type)) as columns
+    jsonb_agg(jsonb_build_object(
+        'column_name', column_name,
+        -- postgis types are user defined, keep their name so geometry columns can be found
+        'data_type', case when udt_name in ('geometry', 'geography') then udt_name::text else data_type::text end,
+        -- `@tankard:hidden` and `@tankard:mask[=kind]` in column comments
+        'hidden', coalesce(comment ~ '@tankard:hidden\M', false),
+        'mask', case when comment ~ '@tankard:mask\M' then coalesce(substring(comment from '@tankard:mask=(\w+)'), 'full') end
+    ) order by ordinal_position) as columns
 from information_schema.columns
+cross join lateral (
+    select col_description(format('%I.%I', table_schema, table_name)::regclass, ordinal_position) as comment
+) c
 where table_schema = 'public'
+    -- tables of extensions, `tankard_` ones included, are not served
+    and not exists (
+        select from pg_depend
+        where classid = 'pg_class'::regclass
+            and objid = format('%I.%I', table_schema, table_name)::regclass
+            and deptype = 'e'
+    )
 group by table_name;
@@ -1,33 +1,82 @@
-use std::{collections::HashMap, str::FromStr};
+use std::{collections::HashMap, convert::Infallible, ops::Deref, str::FromStr};
 
 use axum::{
     async_trait,
-    body::Body,
-    extract::{FromRequest, FromRequestParts, Path, Request, State},
-    http::{header::CONTENT_TYPE, request::Parts, StatusCode},
-    response::{Html, IntoResponse, Response},
+    body::{Body, Bytes},
+    extract::{FromRequest, FromRequestParts, OriginalUri, Path, Query, Request, State},
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        request::Parts,
+        HeaderValue, Method, StatusCode,
+    },
+    response::{IntoResponse, Response},
     routing::get,
     Extension, Form, Json, RequestExt, Router,
 };
 use axum_extra::{extract::JsonLines, TypedHeader};
-use futures::{StreamExt, TryStreamExt};
+use bb8::PooledConnection;
+use bb8_postgres::PostgresConnectionManager;
+use futures::{stream, SinkExt, StreamExt};
 use itertools::Itertools;
-use mediatype::{media_type, MediaType};
 use serde::{Deserialize, Serialize};
-use tokio_postgres::types::Type;
+use tokio_postgres::{error::SqlState, types::Type, NoTls};
 
-use crate::{internal_error, AppState};
+use crate::{
+    api_keys::ApiKey,
+    auth::{Session, User},
+    changes::changes,
+    encoder::{Encoder, Selection, MT_APPLICATION_JSON},
+    hub::ident,
+    internal_error,
+    jwt::Claims,
+    AppState,
+};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub(crate) struct Column {
-    column_name: String,
-    data_type: String,
+    pub(crate) column_name: String,
+    pub(crate) data_type: String,
+    /// Left out unless selected explicitly, `@tankard:hidden` in the column comment.
+    #[serde(default)]
+    pub(crate) hidden: bool,
+    /// Mask applied whenever the column is read, `@tankard:mask=<kind>` in the column comment.
+    #[serde(default)]
+    pub(crate) mask: Option<String>,
+}
+
+impl Column {
+    /// PostGIS `geometry` or `geography` column.
+    pub(crate) fn is_geometry(&self) -> bool {
+        self.data_type == "geometry" || self.data_type == "geography"
+    }
+
+    /// Expression reading `value`, a reference to this column, with its mask applied.
+    ///
+    /// `email` keeps the first character and the domain, `last4` the last four characters,
+    /// anything else masks the whole value.
+    pub(crate) fn masked(&self, value: &str) -> String {
+        match self.mask.as_deref() {
+            None => value.to_string(),
+            Some("email") => format!("regexp_replace({value}::text, '^(.)[^@]*', '\\1***')"),
+            Some("last4") => format!("lpad(right({value}::text, 4), length({value}::text), '*')"),
+            Some(_) => format!("case when {value} is not null then '****' end"),
+        }
+    }
+
+    /// The column as a select list item, masked and keeping its name.
+    pub(crate) fn select(&self) -> String {
+        let column = &self.column_name;
+        match self.mask {
+            None => column.clone(),
+            Some(_) => format!("{} as {column}", self.masked(column)),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub(crate) struct Table {
-    name: String,
-    columns: Vec<Column>,
+    pub(crate) name: String,
+    pub(crate) columns: Vec<Column>,
 }
 
 #[async_trait]
@@ -43,34 +92,47 @@ impl<S: Send + Sync> FromRequestParts<S> for Table {
                 .await
                 // TODO: add error description
                 .map_err(|e| internal_error(e).into_response())?;
-        tables
+        // `/api/users.csv` names the table `users`, the extension is picked up by `Format`
+        let name = match name.rsplit_once('.') {
+            Some((stem, _)) if !tables.contains_key(&name) => stem.to_string(),
+            _ => name,
+        };
+        let columns = tables
             .get(&name)
             // TODO: avoid clone?
             .cloned()
-            .map(|columns| Self { name, columns })
-            .ok_or(StatusCode::NOT_FOUND.into_response())
+            .ok_or(StatusCode::NOT_FOUND.into_response())?;
+        if let Some(key) = parts.extensions.get::<ApiKey>() {
+            let action = match parts.method {
+                Method::GET | Method::HEAD => "read",
+                _ => "write",
+            };
+            key.authorize(action, &name)
+                .map_err(IntoResponse::into_response)?;
+        }
+        Ok(Self { name, columns })
     }
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub(crate) struct Select(pub(crate) Vec<String>);
 
-#[async_trait]
-impl<S: Send + Sync> FromRequestParts<S> for Select {
-    type Rejection = Response;
-
-    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let select = match parts.uri.query().map(Select::from_str) {
+impl Select {
+    /// Parse the `select` parameter of a query string, checking it against `columns`.
+    pub(crate) fn from_query(
+        query: &str,
+        columns: &[Column],
+    ) -> Result<Self, (StatusCode, String)> {
+        let select = match query_param(query, "select").map(Select::from_str) {
             Some(Ok(select)) => Ok(select),
-            Some(Err(err)) => Err((StatusCode::INTERNAL_SERVER_ERROR, err).into_response()),
+            Some(Err(err)) => Err((StatusCode::INTERNAL_SERVER_ERROR, err)),
             None => Ok(Self(vec![])),
         }?;
-        let table = Table::from_request_parts(parts, state).await?;
 
         let bad_selects: Vec<_> = select
             .0
             .iter()
-            .filter(|&s| !table.columns.iter().any(|c| &c.column_name == s))
+            .filter(|&s| !columns.iter().any(|c| &c.column_name == s))
             .map(|s| s.as_str())
             .collect();
 
@@ -78,12 +140,194 @@ impl<S: Send + Sync> FromRequestParts<S> for Select {
             Ok(select)
         } else {
             // TODO: better error response
-            Err((StatusCode::BAD_REQUEST, bad_selects.join(",")).into_response())
+            Err((StatusCode::BAD_REQUEST, bad_selects.join(",")))
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Select {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let table = Table::from_request_parts(parts, state).await?;
+        Self::from_query(parts.uri.query().unwrap_or_default(), &table.columns)
+            .map_err(IntoResponse::into_response)
+    }
+}
+
+/// Quote a value as an SQL string literal.
+pub(crate) fn literal(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Operator {
+    Eq(String),
+    Neq(String),
+    Lt(String),
+    Lte(String),
+    Gt(String),
+    Gte(String),
+    /// `in.(a,b)`, values cannot contain commas or parentheses.
+    In(Vec<String>),
+    /// Bounding box `x1,y1,x2,y2` in the SRID of the column, for geometry columns.
+    Bbox(f64, f64, f64, f64),
+}
+
+/// A `column=op.value` query parameter.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Filter {
+    pub(crate) column: String,
+    pub(crate) operator: Operator,
+}
+
+impl Filter {
+    /// Values are inlined as literals, since `COPY` does not take parameters.
+    fn condition(&self, table: &str, columns: &[Column]) -> String {
+        let column = &self.column;
+        match &self.operator {
+            Operator::Eq(value) => format!("{column} = {}", literal(value)),
+            Operator::Neq(value) => format!("{column} <> {}", literal(value)),
+            Operator::Lt(value) => format!("{column} < {}", literal(value)),
+            Operator::Lte(value) => format!("{column} <= {}", literal(value)),
+            Operator::Gt(value) => format!("{column} > {}", literal(value)),
+            Operator::Gte(value) => format!("{column} >= {}", literal(value)),
+            Operator::In(values) if values.is_empty() => "false".to_string(),
+            Operator::In(values) => {
+                format!(
+                    "{column} in ({})",
+                    values.iter().map(|v| literal(v)).join(",")
+                )
+            }
+            Operator::Bbox(x1, y1, x2, y2) => {
+                let data_type = columns
+                    .iter()
+                    .find(|c| &c.column_name == column)
+                    .map_or("geometry", |c| c.data_type.as_str());
+                // geography is always lon/lat, geometry columns are registered with their SRID
+                let srid = match data_type {
+                    "geography" => "4326".to_string(),
+                    _ => format!(
+                        "Find_SRID('public', {}, {})",
+                        literal(table),
+                        literal(column)
+                    ),
+                };
+                format!("{column} && ST_MakeEnvelope({x1}, {y1}, {x2}, {y2}, {srid})::{data_type}")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct Filters(pub(crate) Vec<Filter>);
+
+impl Filters {
+    /// `where` clause joining all filters, empty when there are none.
+    pub(crate) fn clause(&self, table: &str, columns: &[Column]) -> String {
+        if self.0.is_empty() {
+            String::new()
+        } else {
+            format!(" where {}", self.condition(table, columns))
+        }
+    }
+
+    /// The filters as one boolean expression, `true` without any.
+    pub(crate) fn condition(&self, table: &str, columns: &[Column]) -> String {
+        if self.0.is_empty() {
+            "true".to_string()
+        } else {
+            self.0
+                .iter()
+                .map(|f| f.condition(table, columns))
+                .join(" and ")
+        }
+    }
+
+    /// Parse the filters of a query string, the parameters naming one of `columns`.
+    ///
+    /// Other parameters, like `select`, `format` or cache busters, are left to their extractors.
+    pub(crate) fn from_query(
+        query: &str,
+        columns: &[Column],
+    ) -> Result<Self, (StatusCode, String)> {
+        let pairs = serde_urlencoded::from_str::<Vec<(String, String)>>(query)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        let filters = pairs
+            .iter()
+            .filter(|(key, _)| columns.iter().any(|c| &c.column_name == key))
+            .map(|(key, value)| Filter::from_str(&format!("{key}={value}")))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+        let bad_filters: Vec<_> = filters
+            .iter()
+            .filter(|f| {
+                matches!(f.operator, Operator::Bbox(..))
+                    && !columns
+                        .iter()
+                        .any(|c| c.column_name == f.column && c.is_geometry())
+            })
+            .map(|f| f.column.as_str())
+            .collect();
+
+        if bad_filters.is_empty() {
+            Ok(Self(filters))
+        } else {
+            Err((StatusCode::BAD_REQUEST, bad_filters.join(",")))
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Filters {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let table = Table::from_request_parts(parts, state).await?;
+        Self::from_query(parts.uri.query().unwrap_or_default(), &table.columns)
+            .map_err(IntoResponse::into_response)
+    }
+}
+
+/// Find the raw `name=value` pair of a query parameter.
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .find(|pair| pair.split('=').next() == Some(name))
+}
+
+/// Encoder requested with the `format` query parameter or a path extension like
+/// `/api/users.csv`, overriding `Accept`.
+pub(crate) struct Format(Option<&'static dyn Encoder>);
+
+#[async_trait]
+impl FromRequestParts<AppState> for Format {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        AppState { encoders, .. }: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let path = Path::<String>::from_request_parts(parts, &()).await.ok();
+        let extension = path
+            .as_ref()
+            .and_then(|Path(name)| name.rsplit_once('.'))
+            .map(|(_, extension)| extension);
+        let format = query_param(parts.uri.query().unwrap_or_default(), "format")
+            .and_then(|pair| pair.split_once('='));
+        match format.map(|(_, format)| format).or(extension) {
+            Some(format) => encoders
+                .by_extension(format)
+                .map(|encoder| Self(Some(encoder)))
+                .ok_or((StatusCode::NOT_ACCEPTABLE, format.to_string()).into_response()),
+            None => Ok(Self(None)),
         }
     }
 }
 
-pub(crate) struct JsonOrForm<T>(T);
+pub(crate) struct JsonOrForm<T>(pub(crate) T);
 
 #[async_trait]
 impl<S: Send + Sync, T: 'static> FromRequest<S> for JsonOrForm<T>
@@ -114,93 +358,397 @@ where
     }
 }
 
-pub(crate) fn router() -> Router<AppState> {
-    Router::new().route("/", get(get_table).post(set_table))
+/// Request body of `set_table`: rows to merge, or a binary `COPY` stream.
+pub(crate) enum Payload {
+    Rows(serde_json::Value),
+    CopyBinary(Body),
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequest<S> for Payload {
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let content_type = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+
+        match content_type {
+            Some(content_type)
+                if content_type.starts_with("application/vnd.postgresql.copy-binary") =>
+            {
+                Ok(Self::CopyBinary(req.into_body()))
+            }
+            _ => JsonOrForm::from_request(req, state)
+                .await
+                .map(|JsonOrForm(rows)| Self::Rows(rows)),
+        }
+    }
+}
+
+/// CSV dialect knobs passed on to `COPY`.
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct CsvDialect {
+    delimiter: Option<String>,
+    null: Option<String>,
+    header: Option<bool>,
+    quote: Option<String>,
+}
+
+impl CsvDialect {
+    pub(crate) fn options(&self) -> Result<String, (StatusCode, String)> {
+        fn single_char(name: &str, s: &str) -> Result<String, (StatusCode, String)> {
+            if s.chars().count() == 1 {
+                Ok(format!("{name} {}", literal(s)))
+            } else {
+                Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("{name} must be a single character"),
+                ))
+            }
+        }
+
+        let mut options = vec![
+            "format csv".to_string(),
+            format!("header {}", self.header.unwrap_or(true)),
+        ];
+        if let Some(delimiter) = &self.delimiter {
+            options.push(single_char("delimiter", delimiter)?);
+        }
+        if let Some(quote) = &self.quote {
+            options.push(single_char("quote", quote)?);
+        }
+        if let Some(null) = &self.null {
+            options.push(format!("null {}", literal(null)));
+        }
+        Ok(options.join(","))
+    }
+}
+
+type Pool = bb8::Pool<PostgresConnectionManager<NoTls>>;
+
+/// Role of anonymous requests unless `TANKARD_ANON_ROLE` is set, with no privileges but those
+/// granted to it, like `html.sql` does for the index page.
+pub(crate) const ANON_ROLE: &str = "tankard_anon";
+
+/// Roles requests switch to when their token has none, and request headers exposed to policies.
+#[derive(Debug)]
+pub(crate) struct ContextConfig {
+    user_role: Option<String>,
+    api_key_role: Option<String>,
+    anon_role: String,
+    headers: Vec<String>,
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        Self {
+            user_role: None,
+            api_key_role: None,
+            anon_role: ANON_ROLE.to_string(),
+            headers: vec![
+                "user-agent".to_string(),
+                "x-forwarded-for".to_string(),
+                "x-request-id".to_string(),
+            ],
+        }
+    }
+}
+
+impl ContextConfig {
+    /// `TANKARD_USER_ROLE`, `TANKARD_API_KEY_ROLE` and `TANKARD_ANON_ROLE` for signed in
+    /// requests, those with an API key acting as no user and anonymous ones, and the comma
+    /// separated `TANKARD_CONTEXT_HEADERS`. Signed in requests get the role of the pool without a
+    /// user role, API keys the anon role without one of their own, and anonymous requests
+    /// [`ANON_ROLE`] without an anon role.
+    pub(crate) fn load() -> Self {
+        let mut config = Self::default();
+        if let Ok(role) = std::env::var("TANKARD_USER_ROLE") {
+            config = config.user_role(&role);
+        }
+        if let Ok(role) = std::env::var("TANKARD_API_KEY_ROLE") {
+            config = config.api_key_role(&role);
+        }
+        if let Ok(role) = std::env::var("TANKARD_ANON_ROLE") {
+            config = config.anon_role(&role);
+        }
+        if let Ok(headers) = std::env::var("TANKARD_CONTEXT_HEADERS") {
+            config = config.headers(headers.split(',').map(str::trim));
+        }
+        config
+    }
+
+    pub(crate) fn user_role(mut self, role: &str) -> Self {
+        self.user_role = Some(role.to_string());
+        self
+    }
+
+    pub(crate) fn api_key_role(mut self, role: &str) -> Self {
+        self.api_key_role = Some(role.to_string());
+        self
+    }
+
+    pub(crate) fn anon_role(mut self, role: &str) -> Self {
+        self.anon_role = role.to_string();
+        self
+    }
+
+    pub(crate) fn headers<'a>(mut self, headers: impl IntoIterator<Item = &'a str>) -> Self {
+        self.headers = headers
+            .into_iter()
+            .filter(|header| !header.is_empty())
+            .map(str::to_string)
+            .collect();
+        self
+    }
+}
+
+/// Who is asking and how, for row-level security policies.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Context {
+    pub(crate) claims: Claims,
+    pub(crate) user: Option<User>,
+    /// The session of `user`, long-lived connections check it was not ended.
+    pub(crate) session: Option<Session>,
+    pub(crate) api_key: Option<ApiKey>,
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) headers: serde_json::Map<String, serde_json::Value>,
+    /// The `role` claim, or the role of [`ContextConfig`] for signed in, API key or anonymous
+    /// requests.
+    pub(crate) role: Option<String>,
 }
 
-const MT_TEXT_HTML: MediaType = media_type!(TEXT / HTML);
-const MT_APPLICATION_JSON: MediaType = media_type!(APPLICATION / JSON);
-const MT_TEXT_CSV: MediaType = media_type!(TEXT / CSV);
+impl Context {
+    /// Statements setting up a transaction with this context.
+    fn statements(&self) -> String {
+        let user_id = self
+            .user
+            .as_ref()
+            .map(|user| user.id.as_str())
+            .or_else(|| self.claims.0.as_ref()?.get("sub")?.as_str())
+            .unwrap_or_default();
+        let claims = self
+            .claims
+            .0
+            .as_ref()
+            .map(|claims| serde_json::Value::Object(claims.clone()).to_string())
+            .unwrap_or_default();
+        let api_key = self
+            .api_key
+            .as_ref()
+            .map(|key| key.name.as_str())
+            .unwrap_or_default();
+        let headers = serde_json::Value::Object(self.headers.clone()).to_string();
+        let settings = [
+            ("tankard.user_id", user_id),
+            ("tankard.api_key", api_key),
+            ("tankard.method", &self.method),
+            ("tankard.path", &self.path),
+            ("tankard.headers", &headers),
+            ("request.jwt.claims", &claims),
+        ]
+        .iter()
+        .map(|(name, value)| format!("set_config('{name}', {}, true)", literal(value)))
+        .join(",");
+        let role = self
+            .role
+            .as_ref()
+            .map(|role| format!("set local role {};", ident(role)))
+            .unwrap_or_default();
+        format!("begin;select {settings};{role}")
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Context {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let headers = state
+            .contexts
+            .headers
+            .iter()
+            .filter_map(|name| {
+                let value = parts.headers.get(name)?.to_str().ok()?;
+                Some((name.clone(), value.into()))
+            })
+            .collect();
+        let claims = Claims::from_request_parts(parts, state).await?;
+        let user = parts.extensions.get::<User>().cloned();
+        let session = parts.extensions.get::<Session>().cloned();
+        let api_key = parts.extensions.get::<ApiKey>().cloned();
+        let contexts = state.contexts;
+        // only signed in requests may get the role of the pool
+        let role = match (claims.role(), &user, &api_key) {
+            (Some(role), _, _) => Some(role.to_string()),
+            (None, Some(_), _) => contexts.user_role.clone(),
+            (None, None, Some(_)) => contexts
+                .api_key_role
+                .clone()
+                .or_else(|| Some(contexts.anon_role.clone())),
+            (None, None, None) => Some(contexts.anon_role.clone()),
+        };
+        Ok(Self {
+            claims,
+            user,
+            session,
+            api_key,
+            method: parts.method.to_string(),
+            // routes nested under `/api` see their path with the prefix stripped
+            path: parts
+                .extensions
+                .get::<OriginalUri>()
+                .map_or(&parts.uri, |OriginalUri(uri)| uri)
+                .path()
+                .to_string(),
+            headers,
+            role,
+        })
+    }
+}
+
+/// Connection in a transaction with the [`Context`] of a request, committed when dropped unless
+/// [`Transaction::commit`] was called.
+///
+/// The commit on drop is queued behind the statements of the request, so streamed reads keep the
+/// connection until they are read to the end rather than being buffered. Its errors go nowhere,
+/// writes commit explicitly instead.
+pub(crate) struct Transaction {
+    conn: Option<PooledConnection<'static, PostgresConnectionManager<NoTls>>>,
+    /// Role switched to, `None` for the role of the pool.
+    pub(crate) role: Option<String>,
+}
+
+impl Transaction {
+    /// Begin a transaction, setting `tankard.user_id`, `tankard.api_key`, `tankard.method`,
+    /// `tankard.path`, `tankard.headers` and `request.jwt.claims` before switching role.
+    pub(crate) async fn begin(
+        pool: &'static Pool,
+        context: &Context,
+    ) -> Result<Self, (StatusCode, String)> {
+        let tx = Self {
+            conn: Some(pool.get().await.map_err(internal_error)?),
+            role: context.role.clone(),
+        };
+        tx.batch_execute(&context.statements())
+            .await
+            .map_err(|err| match err.code() {
+                Some(&SqlState::INSUFFICIENT_PRIVILEGE | &SqlState::INVALID_PARAMETER_VALUE) => {
+                    (StatusCode::FORBIDDEN, err.to_string())
+                }
+                _ => internal_error(err),
+            })?;
+        Ok(tx)
+    }
+
+    /// Commit, failing with what failed at commit time, like deferred constraints.
+    pub(crate) async fn commit(mut self) -> Result<(), (StatusCode, String)> {
+        let conn = self.conn.take().expect("taken once");
+        conn.batch_execute("commit;")
+            .await
+            .map_err(|err| match err.code() {
+                Some(code) if code.code().starts_with("23") => {
+                    (StatusCode::CONFLICT, err.to_string())
+                }
+                _ => internal_error(err),
+            })
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Transaction {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let context = Context::from_request_parts(parts, state).await?;
+        Self::begin(state.pool, &context)
+            .await
+            .map_err(IntoResponse::into_response)
+    }
+}
+
+impl Deref for Transaction {
+    type Target = tokio_postgres::Client;
+
+    fn deref(&self) -> &Self::Target {
+        self.conn.as_ref().expect("taken on drop only")
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            // ends failed transactions as well, with a rollback
+            tokio::spawn(async move { _ = conn.batch_execute("commit;").await });
+        }
+    }
+}
 
-const AVAILABLE: &[MediaType] = &[MT_TEXT_HTML, MT_APPLICATION_JSON, MT_TEXT_CSV];
+pub(crate) fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(get_table).post(set_table))
+        .route("/changes", get(changes))
+}
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn get_table(
     Table {
         name: table,
         columns,
     }: Table,
     Select(select): Select,
+    filters: Filters,
+    Format(format): Format,
+    Query(csv): Query<CsvDialect>,
     TypedHeader(accept): TypedHeader<headers_accept::Accept>,
-    State(AppState { pool, .. }): State<AppState>,
+    State(AppState {
+        encoders,
+        privileges,
+        ..
+    }): State<AppState>,
+    conn: Transaction,
 ) -> Response {
-    let conn = match pool.get().await.map_err(internal_error) {
-        Ok(conn) => conn,
+    let Some(encoder) = format.or_else(|| encoders.negotiate(&accept)) else {
+        return StatusCode::NOT_ACCEPTABLE.into_response();
+    };
+    let select = match privileges
+        .authorize(&conn, &table, &columns, select, &filters)
+        .await
+    {
+        Ok(select) => select,
         Err(err) => return err.into_response(),
     };
-    match accept.negotiate(AVAILABLE) {
-        Some(mt) if mt == &MT_APPLICATION_JSON => {
-            let select = if !select.is_empty() {
-                &format!(
-                    "jsonb_build_object({})",
-                    select.iter().map(|s| format!("'{s}', {s}")).join(",")
-                )
-            } else {
-                &format!("to_json({table})")
-            };
-            conn.copy_out(&(format!("copy (select {select} from {table}) to stdout;")))
-                .await
-                .map(|stream| {
-                    JsonLines::new(
-                        stream
-                            .map_ok(|b| serde_json::from_slice::<serde_json::Value>(&b))
-                            .filter_map(|res| async { res.ok() }),
-                    )
-                })
-                .map_err(internal_error)
-                .into_response()
-        }
-        Some(mt) if mt == &MT_TEXT_CSV => {
-            let select = if !select.is_empty() {
-                &select.join(",")
-            } else {
-                "*"
-            };
-
-            conn.copy_out(
-                &(format!("copy (select {select} from {table}) to stdout with csv header;")),
-            )
-            .await
-            .map(Body::from_stream)
-            .map_err(internal_error)
-            .into_response()
-        }
-        Some(mt) if mt == &MT_TEXT_HTML => {
-            let head = if !select.is_empty() {
-                select.iter().map(|s| format!("'{s}'")).join(",")
-            } else {
-                columns
-                    .iter()
-                    .map(|Column { column_name, .. }| format!("'{column_name}'"))
-                    .join(",")
-            };
-
-            let select = if !select.is_empty() {
-                &select.iter().map(|s| format!("{s}::text")).join(",")
-            } else {
-                &table
-            };
+    let mut response = encoder
+        .encode(
+            &conn,
+            &Selection {
+                table: &table,
+                columns: &columns,
+                select: &select,
+                filters: &filters,
+                csv: &csv,
+            },
+        )
+        .await;
 
-            // TODO: support other keys than `id`
-            conn.query_one(&(format!(
-                "select html_minify(jinja_render($1, (select jsonb_build_object('head', array[{head}], 'body', (select array_agg(jsonb_build_object('key', id, 'cols', array[{select}])) from {table})))));"
-            )), &[&include_str!("../../tmpl/table.html")])
-            .await
-            .map(|row| Html(row.get::<_, String>(0)))
-            .map_err(internal_error)
-            .into_response()
+    if format.is_some() && encoder.attachment() && response.status().is_success() {
+        let disposition = format!("attachment; filename={table}.{}", encoder.extension());
+        if let Ok(disposition) = HeaderValue::from_str(&disposition) {
+            response
+                .headers_mut()
+                .insert(CONTENT_DISPOSITION, disposition);
         }
-        _ => StatusCode::NOT_ACCEPTABLE.into_response(),
     }
+
+    response
 }
 
 pub(crate) async fn set_table(
@@ -210,13 +758,64 @@ pub(crate) async fn set_table(
     }: Table,
     Select(select): Select,
     TypedHeader(accept): TypedHeader<headers_accept::Accept>,
-    State(AppState { pool, .. }): State<AppState>,
-    JsonOrForm(payload): JsonOrForm<serde_json::Value>,
+    State(AppState { privileges, .. }): State<AppState>,
+    conn: Transaction,
+    payload: Payload,
 ) -> Response {
-    let conn = match pool.get().await.map_err(internal_error) {
-        Ok(conn) => conn,
-        Err(err) => return err.into_response(),
+    let payload = match payload {
+        Payload::Rows(rows) => rows,
+        Payload::CopyBinary(body) => {
+            let target = if !select.is_empty() {
+                format!("{table} ({})", select.join(","))
+            } else {
+                table
+            };
+            let copied = match copy_in(&conn, &target, body).await {
+                Ok(rows) => rows,
+                Err(err) => return err.into_response(),
+            };
+            return conn
+                .commit()
+                .await
+                .map(|()| copied.to_string())
+                .into_response();
+        }
     };
+    match accept.negotiate([&MT_APPLICATION_JSON]) {
+        Some(mt) if mt == &MT_APPLICATION_JSON => {
+            // the merged rows are returned, as far as the role may see them
+            let select = match privileges
+                .authorize(&conn, &table, &columns, select, &Filters::default())
+                .await
+            {
+                Ok(select) => select,
+                Err(err) => return err.into_response(),
+            };
+            // TODO: validate input
+            let statement = merge(&table, &columns, &select);
+            // buffered, so the response tells whether the rows were committed
+            let rows = match conn
+                .query_typed(&statement, &[(&payload, Type::JSONB)])
+                .await
+            {
+                Ok(rows) => rows,
+                Err(err) => return internal_error(err).into_response(),
+            };
+            if let Err(err) = conn.commit().await {
+                return err.into_response();
+            }
+            JsonLines::new(stream::iter(
+                rows.into_iter()
+                    .map(|row| Ok::<_, Infallible>(row.get::<_, serde_json::Value>(0))),
+            ))
+            .into_response()
+        }
+        _ => StatusCode::NOT_ACCEPTABLE.into_response(),
+    }
+}
+
+/// Statement merging a JSON array of rows (`$1`) into `table`, returning `select` of each row.
+pub(crate) fn merge(table: &str, columns: &[Column], select: &[String]) -> String {
     // TODO: do not map db genarated columns
     // TODO: coalesce primary key inserts
     let json_cols = columns
@@ -225,6 +824,7 @@ pub(crate) async fn set_table(
             |Column {
                  column_name,
                  data_type,
+                 ..
              }| format!("{column_name} {data_type}"),
         )
         .join(",");
@@ -252,37 +852,52 @@ pub(crate) async fn set_table(
     let select = if !select.is_empty() {
         &format!(
             "jsonb_build_object({})",
-            select.iter().map(|s| format!("'{s}', e.{s}")).join(",")
+            select
+                .iter()
+                .filter_map(|s| columns.iter().find(|c| &c.column_name == s))
+                .map(|c| format!(
+                    "'{}', {}",
+                    c.column_name,
+                    c.masked(&format!("e.{}", c.column_name))
+                ))
+                .join(",")
         )
     } else {
         "to_json(e.*)"
     };
-    match accept.negotiate(AVAILABLE) {
-        Some(mt) if mt == &MT_APPLICATION_JSON => {
-            // TODO: validate input
-            let statement = format!(
-                r#"
-                    merge into {table} e
-                    using (select * from json_table($1, '$' columns (_drop bool,{json_cols}))) i
-                    on e.id = i.id
-                    when not matched then insert ({ins_cols}) values ({ins_col_vals})
-                    when matched and i._drop = false then update set {upd_cols}
-                    when matched then delete
-                    returning {select};
-                "#,
-            );
-            conn.query_typed_raw(&statement, [(payload, Type::JSONB)])
-                .await
-                .map(|stream| {
-                    JsonLines::new(
-                        stream.map(|res| res.map(|row| row.get::<_, serde_json::Value>(0))),
-                    )
-                })
-                .map_err(internal_error)
-                .into_response()
-        }
-        _ => StatusCode::NOT_ACCEPTABLE.into_response(),
+    format!(
+        r#"
+            merge into {table} e
+            using (select * from json_table($1, '$' columns (_drop bool,{json_cols}))) i
+            on e.id = i.id
+            when not matched then insert ({ins_cols}) values ({ins_col_vals})
+            when matched and i._drop = false then update set {upd_cols}
+            when matched then delete
+            returning {select};
+        "#,
+    )
+}
+
+/// Stream a binary `COPY` body into `target`, returning the number of rows copied.
+async fn copy_in(
+    conn: &tokio_postgres::Client,
+    target: &str,
+    body: Body,
+) -> Result<u64, (StatusCode, String)> {
+    let sink = conn
+        .copy_in::<_, Bytes>(&format!("copy {target} from stdin with (format binary);"))
+        .await
+        .map_err(internal_error)?;
+    futures::pin_mut!(sink);
+
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        sink.send(chunk.map_err(internal_error)?)
+            .await
+            .map_err(internal_error)?;
     }
+
+    sink.finish().await.map_err(internal_error)
 }
 
 #[cfg(test)]
@@ -293,14 +908,50 @@ mod tests {
         body::Body,
         extract::Request,
         http::{
-            header::{ACCEPT, CONTENT_TYPE},
+            header::{ACCEPT, AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_TYPE, USER_AGENT},
             StatusCode,
         },
     };
+    use futures::StreamExt;
     use http_body_util::BodyExt;
+    use serde::Deserialize;
+    use serde_json::json;
     use tower::ServiceExt;
 
-    use crate::tests::setup_app;
+    use super::{Column, ContextConfig, Filter, Filters, Operator};
+    use crate::{
+        changes,
+        hub::{Hub, Limits},
+        jwt::{
+            tests::{token, SECRET},
+            Keys,
+        },
+        rate_limits::RateLimits,
+        tests::{anonymous, setup_app, setup_pool},
+    };
+
+    #[test]
+    fn filters_other_parameters() -> Result<(), Box<dyn Error>> {
+        let columns = vec![Column {
+            column_name: "username".to_string(),
+            data_type: "text".to_string(),
+            hidden: false,
+            mask: None,
+        }];
+
+        let filters = Filters::from_query("select=username&_=1&username=eq.one&nope=x", &columns)
+            .map_err(|(_, e)| e)?;
+
+        assert_eq!(
+            filters,
+            Filters(vec![Filter {
+                column: "username".to_string(),
+                operator: Operator::Eq("one".to_string()),
+            }])
+        );
+
+        Ok(())
+    }
 
     #[tokio::test]
     async fn users_html() -> Result<(), Box<dyn Error>> {
@@ -391,32 +1042,324 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn users_csv() -> Result<(), Box<dyn Error>> {
-        let (conn, app) = setup_app("f52d7679-f08d-4830-9cbb-11cf4dce6742").await?;
+    async fn users_json_jwt() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("7a2c9e4f-1b6d-4f83-a5e2-9d0c3b8f6a14").await?;
         conn.batch_execute(
-            "insert into users (username, salt, passhash, email) values ('one', '', '', 'foo'), ('two', '', '', 'foo'), ('three', '', '', 'foo');",
-        ).await?;
+            "do $$ begin create role tankard_reader; exception when duplicate_object or unique_violation then null; end $$; grant select on users to tankard_reader; alter table users enable row level security; create policy own on users using (username = current_setting('request.jwt.claims')::jsonb ->> 'sub'); insert into users (username, salt, passhash) values ('one', '', ''), ('two', '', '');",
+        )
+        .await?;
 
-        let response = app
-            .oneshot(
+        let get = |token: String| {
+            app.clone().oneshot(
                 Request::builder()
-                    .uri("/api/users?select=username,email")
-                    .header(ACCEPT, "text/csv")
-                    .body(Body::empty())?,
+                    .uri("/api/users?select=username")
+                    .header(ACCEPT, "application/json")
+                    .header(AUTHORIZATION, format!("Bearer {token}"))
+                    .body(Body::empty())
+                    .unwrap(),
             )
-            .await?;
+        };
+
+        let response = get(token(json!({ "role": "tankard_reader", "sub": "two" }))).await?;
 
         assert_eq!(response.status(), StatusCode::OK);
         assert_eq!(
-            response.into_body().collect().await?.to_bytes(),
-            "username,email\none,foo\ntwo,foo\nthree,foo\n"
+            "{\"username\":\"two\"}\n",
+            response.into_body().collect().await?.to_bytes()
         );
 
+        let response = get(token(json!({ "role": "tankard_nobody" }))).await?;
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = get("nope".to_string()).await?;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
         Ok(())
     }
 
     #[tokio::test]
-    async fn users_csv_empty() -> Result<(), Box<dyn Error>> {
+    async fn users_changes_jwt() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("d3a9f1c7-5e2b-4c84-9f6a-1b7e0d4c2a95").await?;
+        conn.batch_execute(
+            "do $$ begin create role tankard_reader; exception when duplicate_object or unique_violation then null; end $$; grant select on users to tankard_reader; alter table users enable row level security; create policy own on users using (username = current_setting('request.jwt.claims')::jsonb ->> 'sub');",
+        )
+        .await?;
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users/changes?select=username")
+                    .header(ACCEPT, "*/*")
+                    .header(
+                        AUTHORIZATION,
+                        format!(
+                            "Bearer {}",
+                            token(json!({ "role": "tankard_reader", "sub": "one" }))
+                        ),
+                    )
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        conn.batch_execute(
+            "insert into users (username, salt, passhash) values ('two', '', ''); insert into users (username, salt, passhash) values ('one', '', '');",
+        )
+        .await?;
+
+        let mut events = response.into_body().into_data_stream();
+        let event = events.next().await.unwrap()?;
+
+        assert!(std::str::from_utf8(&event)?.contains(r#""new":{"username":"one"}"#));
+
+        // deletes of rows the role could not see are left out
+        conn.batch_execute(
+            "delete from users where username = 'two'; delete from users where username = 'one';",
+        )
+        .await?;
+        let event = events.next().await.unwrap()?;
+        let event = std::str::from_utf8(&event)?;
+
+        assert!(event.contains("event: delete\n"));
+        assert!(event.contains(r#""old":{"username":"one"}"#));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_json_anonymous() -> Result<(), Box<dyn Error>> {
+        let db_name = "6f2c8a4e-0d9b-4e37-a1f5-c7b3e9d2f068";
+        let (conn, _) = setup_app(db_name).await?;
+        conn.batch_execute(
+            "insert into users (username, salt, passhash) values ('one', '', 'secret');",
+        )
+        .await?;
+        let current_user = conn
+            .query_one("select current_user::text;", &[])
+            .await?
+            .get::<_, String>(0);
+        let (pool, config) = setup_pool(db_name).await?;
+        let app = crate::app(
+            pool,
+            Hub::new(config, Limits::default()),
+            changes::CHANNEL,
+            Keys::default().secret(SECRET),
+            RateLimits::default(),
+            ContextConfig::default(),
+        )
+        .await?;
+
+        let get = |select: &str, authorization: Option<String>| {
+            let mut request = Request::builder()
+                .uri(format!("/api/users?select={select}"))
+                .header(ACCEPT, "application/json");
+            if let Some(authorization) = authorization {
+                request = request.header(AUTHORIZATION, authorization);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        // anonymous requests get `tankard_anon`, granted what the index page needs
+        let response = get("passhash", None).await?;
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = get("username", None).await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/listen/users_event")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = get(
+            "passhash",
+            Some(format!(
+                "Bearer {}",
+                token(json!({ "sub": "one", "role": current_user }))
+            )),
+        )
+        .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_csv() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("f52d7679-f08d-4830-9cbb-11cf4dce6742").await?;
+        conn.batch_execute(
+            "insert into users (username, salt, passhash, email) values ('one', '', '', 'foo'), ('two', '', '', 'foo'), ('three', '', '', 'foo');",
+        ).await?;
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users?select=username,email")
+                    .header(ACCEPT, "text/csv")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.into_body().collect().await?.to_bytes(),
+            "username,email\none,foo\ntwo,foo\nthree,foo\n"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_csv_context() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("c4e8a2f6-9d1b-4b37-8e5a-3f7c0a9d2b61").await?;
+        conn.batch_execute(
+            "do $$ begin create role tankard_reader; exception when duplicate_object or unique_violation then null; end $$; grant select on users to tankard_reader; alter table users enable row level security; create policy own on users using (id::text = current_setting('tankard.user_id') and current_setting('tankard.method') = 'GET' and current_setting('tankard.path') = '/api/users.csv' and current_setting('tankard.headers')::jsonb ->> 'user-agent' = 'test'); insert into users (id, username, salt, passhash, email) values ('00000000-0000-0000-0000-000000000001', 'one', '', '', 'foo'), ('00000000-0000-0000-0000-000000000002', 'two', '', '', 'foo');",
+        )
+        .await?;
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users.csv?select=username,email")
+                    .header(ACCEPT, "*/*")
+                    .header(USER_AGENT, "test")
+                    .header(
+                        AUTHORIZATION,
+                        format!(
+                            "Bearer {}",
+                            token(json!({
+                                "role": "tankard_reader",
+                                "sub": "00000000-0000-0000-0000-000000000002"
+                            }))
+                        ),
+                    )
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.into_body().collect().await?.to_bytes(),
+            "username,email\ntwo,foo\n"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_csv_privileges() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("e9b3c7a1-4f2d-4e68-b1a5-8c0d6f3e9a27").await?;
+        conn.batch_execute(
+            "do $$ begin create role tankard_limited; exception when duplicate_object or unique_violation then null; end $$; grant select (username, email) on users to tankard_limited; insert into users (username, salt, passhash, email) values ('one', '', '', 'foo');",
+        )
+        .await?;
+
+        let get = |uri: &str, role: &str| {
+            app.clone().oneshot(
+                Request::builder()
+                    .uri(uri)
+                    .header(ACCEPT, "text/csv")
+                    .header(
+                        AUTHORIZATION,
+                        format!("Bearer {}", token(json!({ "role": role }))),
+                    )
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = get("/api/users", "tankard_limited").await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.into_body().collect().await?.to_bytes(),
+            "username,email\none,foo\n"
+        );
+
+        let response = get("/api/users?select=username,passhash", "tankard_limited").await?;
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            response.into_body().collect().await?.to_bytes(),
+            "permission denied for column passhash of table users"
+        );
+
+        let response = get("/api/users?id=eq.nope", "tankard_limited").await?;
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_csv_masked() -> Result<(), Box<dyn Error>> {
+        let db_name = "4a7e2c9f-1b3d-4f86-a0c5-9e2d7b1f4c38";
+        let (conn, _) = setup_app(db_name).await?;
+        conn.batch_execute(
+            "comment on column users.email is '@tankard:mask=email'; insert into users (username, salt, passhash, email) values ('one', '', 'secret', 'foo@example.com');",
+        )
+        .await?;
+        // comments are read along with the schema, when the app is built
+        let (pool, config) = setup_pool(db_name).await?;
+        let app = crate::app(
+            pool,
+            Hub::new(config, Limits::default()),
+            changes::CHANNEL,
+            Keys::default(),
+            RateLimits::default(),
+            anonymous(pool).await?,
+        )
+        .await?;
+
+        let get = |uri: &str, accept: &str| {
+            app.clone().oneshot(
+                Request::builder()
+                    .uri(uri)
+                    .header(ACCEPT, accept)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = get("/api/users", "text/csv").await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await?.to_bytes();
+        let body = std::str::from_utf8(&body)?;
+        assert!(body.starts_with("id,added,updated,username,salt,email\n"));
+        assert!(body.ends_with(",one,\"\",f***@example.com\n"));
+
+        let response = get("/api/users?select=username,email", "application/json").await?;
+
+        assert_eq!(
+            response.into_body().collect().await?.to_bytes(),
+            "{\"email\":\"f***@example.com\",\"username\":\"one\"}\n"
+        );
+
+        let response = get("/api/users?select=username,passhash", "text/csv").await?;
+
+        assert_eq!(
+            response.into_body().collect().await?.to_bytes(),
+            "username,passhash\none,secret\n"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_csv_empty() -> Result<(), Box<dyn Error>> {
         let (_, app) = setup_app("d822e1bb-d6b4-46f6-8a37-77ea3bab1ecb").await?;
 
         let response = app
@@ -437,6 +1380,483 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn users_csv_dialect() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("9a7e2c41-6d3b-4f8e-b5a1-c0d2e3f4a5b6").await?;
+        conn.batch_execute(
+            "insert into users (username, salt, passhash, email) values ('one', '', '', 'foo'), ('two', '', '', null);",
+        ).await?;
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users?select=username,email&delimiter=%09&null=NULL&header=false")
+                    .header(ACCEPT, "text/csv")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.into_body().collect().await?.to_bytes(),
+            "one\tfoo\ntwo\tNULL\n"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_copy_binary() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("3c8f1d27-9b4e-4a6c-8d2f-7e1a0b9c8d7e").await?;
+        conn.batch_execute(
+            "insert into users (username, salt, passhash) values ('one', '', ''), ('two', '', ''), ('three', '', '');",
+        ).await?;
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users?select=username,salt,passhash")
+                    .header(ACCEPT, "application/vnd.postgresql.copy-binary")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let copy = response.into_body().collect().await?.to_bytes();
+        assert!(copy.starts_with(b"PGCOPY\n\xff\r\n\0"));
+        // usernames are unique
+        conn.batch_execute("delete from users;").await?;
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/users?select=username,salt,passhash")
+                    .header(ACCEPT, "application/json")
+                    .header(CONTENT_TYPE, "application/vnd.postgresql.copy-binary")
+                    .body(Body::from(copy))?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!("3", response.into_body().collect().await?.to_bytes());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_copy_binary_deferred() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("8b1f6d3e-4a7c-4e92-b5d0-2c9e7a1f3b64").await?;
+        conn.batch_execute(
+            "insert into users (username, salt, passhash) values ('one', '', ''), ('three', '', '');",
+        ).await?;
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users?select=username,salt,passhash")
+                    .header(ACCEPT, "application/vnd.postgresql.copy-binary")
+                    .body(Body::empty())?,
+            )
+            .await?;
+        let copy = response.into_body().collect().await?.to_bytes();
+
+        // checked at commit, after the copy went through
+        conn.batch_execute(
+            "delete from users; create function no_three() returns trigger language plpgsql as $$ begin if new.username = 'three' then raise exception 'no three' using errcode = 'check_violation'; end if; return null; end $$; create constraint trigger no_three after insert on users deferrable initially deferred for each row execute function no_three();",
+        )
+        .await?;
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/users?select=username,salt,passhash")
+                    .header(ACCEPT, "application/json")
+                    .header(CONTENT_TYPE, "application/vnd.postgresql.copy-binary")
+                    .body(Body::from(copy))?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            conn.query_one("select count(*) from users;", &[])
+                .await?
+                .get::<_, i64>(0),
+            0
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_csv_extension() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("6e5d4c3b-2a19-4f08-9e7d-6c5b4a392817").await?;
+        conn.batch_execute(
+            "insert into users (username, salt, passhash, email) values ('one', '', '', 'foo'), ('two', '', '', 'foo');",
+        ).await?;
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users.csv?select=username,email")
+                    .header(ACCEPT, "text/html")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[CONTENT_DISPOSITION],
+            "attachment; filename=users.csv"
+        );
+        assert_eq!(
+            response.into_body().collect().await?.to_bytes(),
+            "username,email\none,foo\ntwo,foo\n"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_format_not_acceptable() -> Result<(), Box<dyn Error>> {
+        let (_, app) = setup_app("1a2b3c4d-5e6f-4a7b-8c9d-0e1f2a3b4c5e").await?;
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users?format=doc")
+                    .header(ACCEPT, "text/html")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::NOT_ACCEPTABLE);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_csv_filter() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("2f1e0d9c-8b7a-4c6d-9e5f-4a3b2c1d0e9f").await?;
+        conn.batch_execute(
+            "insert into users (username, salt, passhash, email) values ('one', '', '', 'foo'), ('two', '', '', 'bar'), ('three', '', '', 'foo');",
+        ).await?;
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users?select=username&email=eq.foo&username=neq.one")
+                    .header(ACCEPT, "text/csv")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.into_body().collect().await?.to_bytes(),
+            "username\nthree\n"
+        );
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users?select=username&username=in.(one,two,nope)")
+                    .header(ACCEPT, "text/csv")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(
+            response.into_body().collect().await?.to_bytes(),
+            "username\none\ntwo\n"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_bad_filter() -> Result<(), Box<dyn Error>> {
+        let (_, app) = setup_app("8c7b6a59-4d3e-4f2a-b1c0-9d8e7f6a5b4c").await?;
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users?username=bbox.0,0,1,1")
+                    .header(ACCEPT, "text/csv")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.into_body().collect().await?.to_bytes(), "username");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_xlsx() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("0b5d3e56-53a4-4f0e-9d0c-8f5b0a4a8d31").await?;
+        conn.batch_execute(
+            "insert into users (username, salt, passhash, email) values ('one', '', '', 'foo'), ('two', '', '', 'foo'), ('three', '', '', 'foo');",
+        ).await?;
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users?select=username,added")
+                    .header(
+                        ACCEPT,
+                        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+                    )
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"
+        );
+        assert!(response
+            .into_body()
+            .collect()
+            .await?
+            .to_bytes()
+            .starts_with(b"PK"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_xlsx_out_of_range() -> Result<(), Box<dyn Error>> {
+        let db_name = "9e4a7c2d-3b6f-4d18-8a5e-1c0f2b9d7e43";
+        let (conn, _) = setup_app(db_name).await?;
+        conn.batch_execute(
+            "alter table users add column balance numeric, add column born date; insert into users (username, salt, passhash, added, balance, born) values ('one', '', '', 'infinity', 12345678901234567890.123, '-infinity'), ('two', '', '', now(), 1.5, '2000-01-01');",
+        ).await?;
+        let (pool, config) = setup_pool(db_name).await?;
+        let app = crate::app(
+            pool,
+            Hub::new(config, Limits::default()),
+            changes::CHANNEL,
+            Keys::default(),
+            RateLimits::default(),
+            anonymous(pool).await?,
+        )
+        .await?;
+
+        for accept in [
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+            "application/vnd.oasis.opendocument.spreadsheet",
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/api/users?select=username,added,balance,born")
+                        .header(ACCEPT, accept)
+                        .body(Body::empty())?,
+                )
+                .await?;
+
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_json_wide() -> Result<(), Box<dyn Error>> {
+        let db_name = "3c7f1a9e-5d2b-4e84-b6a0-8f4d2c1e9b75";
+        let (conn, _) = setup_app(db_name).await?;
+        conn.batch_execute(
+            "do $$ begin for i in 1..120 loop execute format('alter table users add column c%s int default %s', i, i); end loop; end $$; insert into users (username, salt, passhash) values ('one', '', '');",
+        ).await?;
+        let (pool, config) = setup_pool(db_name).await?;
+        let app = crate::app(
+            pool,
+            Hub::new(config, Limits::default()),
+            changes::CHANNEL,
+            Keys::default(),
+            RateLimits::default(),
+            anonymous(pool).await?,
+        )
+        .await?;
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users")
+                    .header(ACCEPT, "application/json")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let row = serde_json::from_slice::<serde_json::Value>(
+            &response.into_body().collect().await?.to_bytes(),
+        )?;
+        assert_eq!(row["username"], "one");
+        assert_eq!(row["c120"], 120);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users")
+                    .header(ACCEPT, "application/xml")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(
+            String::from_utf8(response.into_body().collect().await?.to_bytes().into())?
+                .contains("<c120>120</c120>")
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_ods_format() -> Result<(), Box<dyn Error>> {
+        let (_, app) = setup_app("5f0e6c2a-8f3b-4c1d-a3e2-2b7c9d4e1f60").await?;
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users?select=username,email&format=ods")
+                    .header(ACCEPT, "text/html")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "application/vnd.oasis.opendocument.spreadsheet"
+        );
+        assert!(response
+            .into_body()
+            .collect()
+            .await?
+            .to_bytes()
+            .starts_with(b"PK"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_xml() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("e2b7a9c4-1f3d-4e6a-9b8c-5d4f3e2a1b0c").await?;
+        conn.batch_execute(
+            "insert into users (username, salt, passhash, email) values ('one', '', '', 'foo'), ('t&o', '', '', null);",
+        ).await?;
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users?select=username,email")
+                    .header(ACCEPT, "application/xml")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.into_body().collect().await?.to_bytes(),
+            r#"<?xml version="1.0" encoding="UTF-8"?><users><row><username>one</username><email>foo</email></row><row><username>t&amp;o</username><email nil="true"/></row></users>"#
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_yaml() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("7d6c5b4a-3e2f-4a1b-8c9d-0e1f2a3b4c5d").await?;
+        conn.batch_execute(
+            "insert into users (username, salt, passhash, email) values ('one', '', '', 'foo'), ('two', '', '', null);",
+        ).await?;
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users?select=username,email")
+                    .header(ACCEPT, "application/yaml")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.into_body().collect().await?.to_bytes(),
+            "- username: one\n  email: foo\n- username: two\n  email: null\n"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_msgpack() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("4b3a2c1d-0e9f-4d8c-b7a6-5f4e3d2c1b0a").await?;
+        conn.batch_execute(
+            "insert into users (username, salt, passhash) values ('one', '', ''), ('two', '', '');",
+        )
+        .await?;
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users?select=username")
+                    .header(ACCEPT, "application/msgpack")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await?.to_bytes();
+        let mut de = rmp_serde::Deserializer::new(&body[..]);
+        let rows =
+            std::iter::from_fn(|| serde_json::Value::deserialize(&mut de).ok()).collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            vec![json!({ "username": "one" }), json!({ "username": "two" })]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_cbor() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("a0b1c2d3-e4f5-4a6b-8c7d-9e0f1a2b3c4d").await?;
+        conn.batch_execute(
+            "insert into users (username, salt, passhash) values ('one', '', ''), ('two', '', '');",
+        )
+        .await?;
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users?select=username&format=cbor")
+                    .header(ACCEPT, "text/html")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await?.to_bytes();
+        assert_eq!(
+            ciborium::from_reader::<serde_json::Value, _>(&body[..])?,
+            json!([{ "username": "one" }, { "username": "two" }])
+        );
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn users_bad_select() -> Result<(), Box<dyn Error>> {
         let (conn, app) = setup_app("497f06e4-c65d-4e67-8a3c-006f772819f7").await?;
@@ -480,6 +1900,24 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn extension_table_not_found() -> Result<(), Box<dyn Error>> {
+        let (_, app) = setup_app("2e7b4d9a-c3f1-4a68-9b52-d8e0f6a1c374").await?;
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/tankard_webhooks?select=secret")
+                    .header(ACCEPT, "application/json")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn users_post_json() -> Result<(), Box<dyn Error>> {
         let (_, app) = setup_app("d54eddd9-6a92-46fd-9c58-0c5a9b710312").await?;
@@ -0,0 +1,273 @@
+use std::error::Error;
+
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+        HeaderMap, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use bb8_postgres::PostgresConnectionManager;
+use tokio_postgres::NoTls;
+
+use crate::{auth::User, internal_error, AppState};
+
+/// Key of an `Authorization: ApiKey <key>` request, attached to it by [`api_key`].
+#[derive(Debug, Clone)]
+pub(crate) struct ApiKey {
+    pub(crate) name: String,
+    /// Like `read:users`, `write:orders` or `rpc:*`.
+    pub(crate) scopes: Vec<String>,
+}
+
+impl ApiKey {
+    /// Whether one of the scopes covers `action` on `target`, `*` standing for any of either.
+    pub(crate) fn allows(&self, action: &str, target: &str) -> bool {
+        self.scopes.iter().any(|scope| {
+            let (a, t) = scope.split_once(':').unwrap_or((scope, "*"));
+            (a == "*" || a == action) && (t == "*" || t == target)
+        })
+    }
+
+    /// 403 unless one of the scopes covers `action` on `target`.
+    pub(crate) fn authorize(&self, action: &str, target: &str) -> Result<(), (StatusCode, String)> {
+        if self.allows(action, target) {
+            Ok(())
+        } else {
+            Err((
+                StatusCode::FORBIDDEN,
+                format!("api key {} lacks scope {action}:{target}", self.name),
+            ))
+        }
+    }
+}
+
+/// Attach the [`ApiKey`] of the `Authorization` header, if any, and the [`User`] it acts as to
+/// the request. Unknown and expired keys are turned away.
+pub(crate) async fn api_key(
+    State(AppState { pool, .. }): State<AppState>,
+    headers: HeaderMap,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let key = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("ApiKey "));
+    if let Some(key) = key {
+        let conn = match pool.get().await.map_err(internal_error) {
+            Ok(conn) => conn,
+            Err(err) => return err.into_response(),
+        };
+        let row = match conn
+            .query_opt(
+                "select k.name, k.scopes, u.id::text, u.username from tankard_api_key_use($1) k left join users u on u.id = k.user_id;",
+                &[&key.trim()],
+            )
+            .await
+        {
+            Ok(row) => row,
+            Err(err) => return internal_error(err).into_response(),
+        };
+        let Some(row) = row else {
+            return (
+                StatusCode::UNAUTHORIZED,
+                [(WWW_AUTHENTICATE, r#"ApiKey error="invalid_key""#)],
+            )
+                .into_response();
+        };
+        if let (Some(id), Some(username)) = (row.get(2), row.get(3)) {
+            request.extensions_mut().insert(User { id, username });
+        }
+        request.extensions_mut().insert(ApiKey {
+            name: row.get(0),
+            scopes: row.get(1),
+        });
+    }
+    next.run(request).await
+}
+
+/// `api-key create <name> <scope,...> [expires]` prints a new key, `api-key revoke <name>`
+/// revokes one.
+pub(crate) async fn cli(
+    pool: &bb8::Pool<PostgresConnectionManager<NoTls>>,
+    args: &[String],
+) -> Result<(), Box<dyn Error>> {
+    let conn = pool.get().await?;
+    match args {
+        [command, name, scopes, expires @ ..] if command == "create" && expires.len() <= 1 => {
+            let scopes = scopes
+                .split(',')
+                .filter(|scope| !scope.is_empty())
+                .collect::<Vec<_>>();
+            let key = conn
+                .query_one(
+                    "select tankard_api_key_create($1, $2, $3::text::timestamptz);",
+                    &[name, &scopes, &expires.first()],
+                )
+                .await?;
+            println!("{}", key.get::<_, String>(0));
+        }
+        [command, name] if command == "revoke" => {
+            let revoked = conn
+                .query_one("select tankard_api_key_revoke($1);", &[name])
+                .await?;
+            if !revoked.get::<_, bool>(0) {
+                return Err(format!("no api key named {name}").into());
+            }
+        }
+        _ => {
+            return Err(
+                "usage: api-key create <name> <scope,...> [expires] | api-key revoke <name>".into(),
+            )
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use axum::{
+        body::Body,
+        extract::Request,
+        http::{
+            header::{ACCEPT, AUTHORIZATION},
+            StatusCode,
+        },
+    };
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    use super::ApiKey;
+    use crate::{
+        api::ContextConfig,
+        changes,
+        hub::{Hub, Limits},
+        jwt::Keys,
+        rate_limits::RateLimits,
+        tests::{setup_app, setup_pool},
+    };
+
+    #[test]
+    fn allows() {
+        let key = ApiKey {
+            name: "script".to_string(),
+            scopes: vec!["read:users".to_string(), "rpc:*".to_string()],
+        };
+
+        assert!(key.allows("read", "users"));
+        assert!(!key.allows("write", "users"));
+        assert!(!key.allows("read", "orders"));
+        assert!(key.allows("rpc", "anything"));
+    }
+
+    #[tokio::test]
+    async fn users_api_key() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("2f8c6e1a-9b4d-4a73-8e05-d1c7b3f9a642").await?;
+        conn.batch_execute(
+            "insert into users (username, salt, passhash, email) values ('one', '', '', 'foo');",
+        )
+        .await?;
+        let key: String = conn
+            .query_one(
+                "select tankard_api_key_create('script', '{read:users}');",
+                &[],
+            )
+            .await?
+            .get(0);
+
+        let request = |method: &str, key: &str| {
+            app.clone().oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri("/api/users?select=username")
+                    .header(ACCEPT, "text/csv")
+                    .header(AUTHORIZATION, format!("ApiKey {key}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = request("GET", &key).await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.into_body().collect().await?.to_bytes(),
+            "username\none\n"
+        );
+        assert!(conn
+            .query_one("select last_used is not null from tankard_api_keys;", &[])
+            .await?
+            .get::<_, bool>(0));
+
+        let response = request("POST", &key).await?;
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            response.into_body().collect().await?.to_bytes(),
+            "api key script lacks scope write:users"
+        );
+
+        let response = request("GET", "tk_nope").await?;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_api_key_role() -> Result<(), Box<dyn Error>> {
+        let db_name = "7a3e9d1f-4c6b-4f28-b0e5-2d8c1a7f9e34";
+        let (conn, _) = setup_app(db_name).await?;
+        let key: String = conn
+            .query_one(
+                "select tankard_api_key_create('script', '{read:users}');",
+                &[],
+            )
+            .await?
+            .get(0);
+        let current_user = conn
+            .query_one("select current_user::text;", &[])
+            .await?
+            .get::<_, String>(0);
+        let (pool, config) = setup_pool(db_name).await?;
+
+        let get = |contexts: ContextConfig| {
+            let (config, key) = (config.clone(), key.clone());
+            async move {
+                let app = crate::app(
+                    pool,
+                    Hub::new(config, Limits::default()),
+                    changes::CHANNEL,
+                    Keys::default(),
+                    RateLimits::default(),
+                    contexts,
+                )
+                .await?;
+                let response = app
+                    .oneshot(
+                        Request::builder()
+                            .uri("/api/users?select=passhash")
+                            .header(ACCEPT, "text/csv")
+                            .header(AUTHORIZATION, format!("ApiKey {key}"))
+                            .body(Body::empty())?,
+                    )
+                    .await?;
+                Ok::<_, Box<dyn Error>>(response.status())
+            }
+        };
+
+        // keys acting as no user get the anon role, not the role of the pool
+        assert_eq!(get(ContextConfig::default()).await?, StatusCode::FORBIDDEN);
+        assert_eq!(
+            get(ContextConfig::default().api_key_role(&current_user)).await?,
+            StatusCode::OK
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,449 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use axum::{
+    extract::{Query, State},
+    http::{header::LINK, StatusCode, Uri},
+    response::{Html, IntoResponse, Response},
+    Extension,
+};
+use axum_extra::{extract::JsonLines, TypedHeader};
+use chrono::{DateTime, FixedOffset, Utc};
+use futures::stream;
+use headers_accept::Accept;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use tokio_postgres::Row;
+
+use crate::{
+    api::{Column, Context, Transaction},
+    encoder::{MT_APPLICATION_JSON, MT_TEXT_HTML},
+    internal_error, AppState,
+};
+
+/// Entries of a page when the query sets no `limit`.
+pub(crate) const PAGE: i64 = 100;
+/// Most entries of a page.
+pub(crate) const PAGE_MAX: i64 = 1000;
+
+/// Query parameters of `/api/_audit`, times are RFC 3339.
+#[derive(Debug, Deserialize)]
+pub(crate) struct AuditQuery {
+    table: Option<String>,
+    /// Primary key value, any column of composite keys.
+    pk: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    /// Entries after this id, the `Link` of the previous page.
+    after: Option<i64>,
+    limit: Option<i64>,
+}
+
+fn time(value: Option<&str>) -> Result<Option<DateTime<FixedOffset>>, (StatusCode, String)> {
+    value
+        .map(DateTime::parse_from_rfc3339)
+        .transpose()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+/// Columns of `row` the role may select, hidden ones left out and masked ones blanked.
+fn redact(row: Option<Value>, allowed: &HashSet<String>, columns: &[Column]) -> Value {
+    let Some(Value::Object(row)) = row else {
+        return Value::Null;
+    };
+    Value::Object(
+        row.into_iter()
+            .filter_map(|(key, value)| {
+                let column = columns.iter().find(|c| c.column_name == key)?;
+                if column.hidden || !allowed.contains(&key) {
+                    None
+                } else if column.mask.is_some() && !value.is_null() {
+                    Some((key, "****".into()))
+                } else {
+                    Some((key, value))
+                }
+            })
+            .collect(),
+    )
+}
+
+/// Columns that differ between `old` and `new`, as text.
+fn changes(old: &Value, new: &Value) -> Vec<Value> {
+    let text = |row: &Value, key: &str| match row.get(key) {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(value) => value.to_string(),
+    };
+    let keys = [old, new]
+        .into_iter()
+        .filter_map(Value::as_object)
+        .flat_map(Map::keys)
+        .collect::<BTreeSet<_>>();
+    keys.into_iter()
+        .filter(|key| old.get(key.as_str()) != new.get(key.as_str()))
+        .map(|key| json!({ "column": key, "old": text(old, key), "new": text(new, key) }))
+        .collect()
+}
+
+/// `uri` with `after` set, the next page.
+pub(crate) fn next(uri: &Uri, after: i64) -> String {
+    let mut query =
+        serde_urlencoded::from_str::<Vec<(String, String)>>(uri.query().unwrap_or_default())
+            .unwrap_or_default();
+    query.retain(|(name, _)| name != "after");
+    query.push(("after".to_string(), after.to_string()));
+    format!(
+        "{}?{}",
+        uri.path(),
+        serde_urlencoded::to_string(query).unwrap_or_default()
+    )
+}
+
+/// Ids of the entries in `rows` the role of `conn` could have seen the row of, before or after
+/// the write, by grants and row-level security like `/api/:table`. `None` for the role of the
+/// pool, which sees them all.
+async fn visible(
+    conn: &Transaction,
+    rows: &[Row],
+) -> Result<Option<HashSet<i64>>, tokio_postgres::Error> {
+    if conn.role.is_none() {
+        return Ok(None);
+    }
+    let ids = rows.iter().map(|row| row.get("id")).collect::<Vec<i64>>();
+    let tables = rows
+        .iter()
+        .map(|row| row.get("table_name"))
+        .collect::<Vec<String>>();
+    let olds = rows
+        .iter()
+        .map(|row| row.get("old"))
+        .collect::<Vec<Option<Value>>>();
+    let news = rows
+        .iter()
+        .map(|row| row.get("new"))
+        .collect::<Vec<Option<Value>>>();
+    let rows = conn
+        .query(
+            "select audit_id from unnest($1::bigint[], $2::text[], $3::jsonb[], $4::jsonb[]) audit (audit_id, audit_table, audit_old, audit_new)
+             where case when to_regclass(audit_table) is null then false
+               else tankard_visible(audit_table::regclass, audit_old) or tankard_visible(audit_table::regclass, audit_new) end;",
+            &[&ids, &tables, &olds, &news],
+        )
+        .await?;
+    Ok(Some(rows.into_iter().map(|row| row.get(0)).collect()))
+}
+
+/// Recorded writes of tables with `tankard_audit_attach`, as JSON lines or an HTML history.
+///
+/// Entries are read as the server role, `tankard_audit` is not granted to any other. Those of
+/// rows the role could not have seen, before or after the write, are left out like columns it
+/// may not select, so history follows grants and row-level security, deleted rows included.
+/// Pages are `limit` entries by `id`, a `Link` header points to the next.
+pub(crate) async fn audit(
+    uri: Uri,
+    Query(query): Query<AuditQuery>,
+    TypedHeader(accept): TypedHeader<Accept>,
+    Extension(tables): Extension<HashMap<String, Vec<Column>>>,
+    State(AppState {
+        pool, privileges, ..
+    }): State<AppState>,
+    context: Context,
+) -> Response {
+    let html = match accept.negotiate([&MT_APPLICATION_JSON, &MT_TEXT_HTML]) {
+        Some(mt) => mt == &MT_TEXT_HTML,
+        None => return StatusCode::NOT_ACCEPTABLE.into_response(),
+    };
+    if let Some(key) = &context.api_key {
+        if let Err(err) = key.authorize("read", "_audit") {
+            return err.into_response();
+        }
+    }
+    let (from, to) = match (time(query.from.as_deref()), time(query.to.as_deref())) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(err), _) | (_, Err(err)) => return err.into_response(),
+    };
+    let limit = query.limit.unwrap_or(PAGE).clamp(1, PAGE_MAX);
+
+    let audit = match pool.get().await {
+        Ok(audit) => audit,
+        Err(err) => return internal_error(err).into_response(),
+    };
+    let conn = match Transaction::begin(pool, &context).await {
+        Ok(conn) => conn,
+        Err(err) => return err.into_response(),
+    };
+    let allowed = match privileges.columns(&conn).await {
+        Ok(allowed) => allowed,
+        Err(err) => return internal_error(err).into_response(),
+    };
+
+    // entries are read `limit` at a time until there are `limit` the role may see
+    let (mut entries, mut after) = (Vec::new(), query.after);
+    let next = loop {
+        let rows = match audit
+            .query(
+                "select id, table_name, pk, op, old, new, actor, request_id, at from tankard_audit
+                 where ($1::text is null or table_name = $1)
+                 and ($2::text is null or $2 in (select value from jsonb_each_text(pk)))
+                 and ($3::timestamptz is null or at >= $3)
+                 and ($4::timestamptz is null or at < $4)
+                 and ($5::bigint is null or id > $5)
+                 order by id
+                 limit $6;",
+                &[&query.table, &query.pk, &from, &to, &after, &limit],
+            )
+            .await
+        {
+            Ok(rows) => rows,
+            Err(err) => return internal_error(err).into_response(),
+        };
+        let visible = match visible(&conn, &rows).await {
+            Ok(visible) => visible,
+            Err(err) => return internal_error(err).into_response(),
+        };
+        let read = rows.len() as i64;
+
+        for row in rows {
+            let id = row.get::<_, i64>("id");
+            after = Some(id);
+            let table = row.get::<_, String>("table_name");
+            let (Some(allowed), Some(columns)) = (
+                allowed.get(&table).filter(|allowed| !allowed.is_empty()),
+                tables.get(&table),
+            ) else {
+                continue;
+            };
+            if visible
+                .as_ref()
+                .is_some_and(|visible| !visible.contains(&id))
+            {
+                continue;
+            }
+            entries.push(json!({
+                "id": id,
+                "table": table,
+                "pk": row.get::<_, Value>("pk"),
+                "op": row.get::<_, String>("op"),
+                "old": redact(row.get("old"), allowed, columns),
+                "new": redact(row.get("new"), allowed, columns),
+                "actor": row.get::<_, String>("actor"),
+                "request_id": row.get::<_, Option<String>>("request_id"),
+                "at": row.get::<_, DateTime<Utc>>("at").to_rfc3339(),
+            }));
+            if entries.len() as i64 == limit {
+                break;
+            }
+        }
+        if entries.len() as i64 == limit {
+            break after.map(|after| next(&uri, after));
+        }
+        if read < limit {
+            break None;
+        }
+    };
+    drop(audit);
+    let entries = entries.into_iter();
+
+    if !html {
+        let entries = entries
+            .map(Ok::<_, std::convert::Infallible>)
+            .collect::<Vec<_>>();
+        let link = next.map(|next| [(LINK, format!("<{next}>; rel=\"next\""))]);
+        return (link, JsonLines::new(stream::iter(entries))).into_response();
+    }
+    let rows = entries
+        .map(|mut entry| {
+            entry["changes"] = changes(&entry["old"], &entry["new"]).into();
+            if entry["request_id"].is_null() {
+                entry["request_id"] = "".into();
+            }
+            entry
+        })
+        .collect::<Vec<_>>();
+    let link = next
+        .as_ref()
+        .map(|next| [(LINK, format!("<{next}>; rel=\"next\""))]);
+    let html = conn
+        .query_one(
+            "select html_minify(jinja_render($1, $2));",
+            &[
+                &include_str!("../../tmpl/history.html"),
+                &json!({ "rows": rows, "next": next }),
+            ],
+        )
+        .await
+        .map(|row| Html(row.get::<_, String>(0)))
+        .map_err(internal_error);
+    (link, html).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use axum::{
+        body::{Body, Bytes},
+        extract::Request,
+        http::{
+            header::{ACCEPT, AUTHORIZATION, LINK},
+            StatusCode,
+        },
+    };
+    use http_body_util::BodyExt;
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use crate::{jwt, tests::setup_app};
+
+    #[tokio::test]
+    async fn users_audit() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("a3e9c1d7-8b5f-4f20-9c64-2d1b7e8a5f93").await?;
+        conn.batch_execute(
+            "begin; select set_config('tankard.user_id', '00000000-0000-0000-0000-000000000009', true), set_config('tankard.headers', '{\"x-request-id\": \"abc\"}', true); insert into users (id, username, salt, passhash, email) values ('00000000-0000-0000-0000-000000000001', 'one', '', 'secret', 'foo'), ('00000000-0000-0000-0000-000000000002', 'two', '', '', 'foo'); update users set username = 'uno' where username = 'one'; commit;",
+        )
+        .await?;
+
+        let get = |uri: &str, accept: &str| {
+            app.clone().oneshot(
+                Request::builder()
+                    .uri(uri)
+                    .header(ACCEPT, accept)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = get(
+            "/api/_audit?table=users&pk=00000000-0000-0000-0000-000000000001",
+            "application/json",
+        )
+        .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await?.to_bytes();
+        let entries = body
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice::<Value>)
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["op"], "insert");
+        assert_eq!(entries[0]["actor"], "00000000-0000-0000-0000-000000000009");
+        assert_eq!(entries[0]["request_id"], "abc");
+        assert_eq!(entries[0]["new"]["username"], "one");
+        assert!(entries[0]["new"].get("passhash").is_none());
+        assert_eq!(entries[1]["op"], "update");
+        assert_eq!(entries[1]["old"]["username"], "one");
+        assert_eq!(entries[1]["new"]["username"], "uno");
+
+        let response = get("/api/_audit?from=2999-01-01T00:00:00Z", "application/json").await?;
+
+        assert_eq!(response.into_body().collect().await?.to_bytes(), "");
+
+        let response = get("/api/_audit?from=yesterday", "application/json").await?;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = get(
+            "/api/_audit?table=users&pk=00000000-0000-0000-0000-000000000001",
+            "text/html",
+        )
+        .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_audit_pages() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("c81f4d2b-6e3a-4b97-a0d5-9f2e7c1b3a68").await?;
+        conn.batch_execute(
+            "do $$ begin create role tankard_auditor; exception when duplicate_object or unique_violation then null; end $$; grant select on users to tankard_auditor; alter table users enable row level security; create policy users_two on users to tankard_auditor using (username <> 'one'); insert into users (id, username, salt, passhash) values ('00000000-0000-0000-0000-000000000001', 'one', '', ''), ('00000000-0000-0000-0000-000000000002', 'two', '', ''), ('00000000-0000-0000-0000-000000000003', 'three', '', ''); update users set passhash = 'secret';",
+        )
+        .await?;
+
+        let get = |uri: &str, role: Option<&str>| {
+            let mut request = Request::builder()
+                .uri(uri)
+                .header(ACCEPT, "application/json");
+            if let Some(role) = role {
+                request = request.header(
+                    AUTHORIZATION,
+                    format!("Bearer {}", jwt::tests::token(json!({ "role": role }))),
+                );
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+        let entries = |body: Bytes| {
+            body.split(|b| *b == b'\n')
+                .filter(|line| !line.is_empty())
+                .map(serde_json::from_slice::<Value>)
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        let response = get("/api/_audit?table=users&limit=4", None).await?;
+
+        let link = response.headers()[LINK].to_str()?.to_string();
+        let body = entries(response.into_body().collect().await?.to_bytes())?;
+        assert_eq!(body.len(), 4);
+        assert_eq!(
+            link,
+            format!(
+                "</api/_audit?table=users&limit=4&after={}>; rel=\"next\"",
+                body[3]["id"]
+            )
+        );
+
+        let next = link
+            .trim_start_matches('<')
+            .split('>')
+            .next()
+            .unwrap_or_default();
+        let response = get(next, None).await?;
+
+        assert!(response.headers().get(LINK).is_none());
+        let body = entries(response.into_body().collect().await?.to_bytes())?;
+        assert_eq!(body.len(), 2);
+        assert_eq!(body[1]["op"], "update");
+
+        conn.batch_execute("delete from users where username in ('one', 'two');")
+            .await?;
+
+        // pages of entries the role could see, deleted rows included
+        let mut uri = "/api/_audit?table=users&limit=2".to_string();
+        let mut pages = Vec::new();
+        loop {
+            let response = get(&uri, Some("tankard_auditor")).await?;
+
+            assert_eq!(response.status(), StatusCode::OK);
+            let link = response
+                .headers()
+                .get(LINK)
+                .map(|link| link.to_str().map(str::to_string))
+                .transpose()?;
+            pages.push(entries(response.into_body().collect().await?.to_bytes())?);
+            let Some(link) = link else {
+                break;
+            };
+            uri = link
+                .trim_start_matches('<')
+                .split('>')
+                .next()
+                .unwrap_or_default()
+                .to_string();
+        }
+
+        assert_eq!(
+            pages.iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![2, 2, 1]
+        );
+        assert!(pages
+            .iter()
+            .flatten()
+            .all(|entry| entry["pk"]["id"] != "00000000-0000-0000-0000-000000000001"));
+        assert_eq!(pages[2][0]["op"], "delete");
+        assert_eq!(pages[2][0]["old"]["username"], "two");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,396 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{Request, State},
+    http::{
+        header::{CONTENT_TYPE, SET_COOKIE},
+        HeaderMap, StatusCode, Uri,
+    },
+    middleware::Next,
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use axum_extra::{headers::Cookie, TypedHeader};
+use axum_htmx::{HxRedirect, HxRequest};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{api::JsonOrForm, csrf::CsrfToken, internal_error, AppState};
+
+/// Name of the session cookie.
+pub(crate) const COOKIE: &str = "tankard_session";
+const SESSION_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Argon2id hash at the default costs that no password matches.
+const DUMMY_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$c29tZXNhbHRzb21lc2FsdA$AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA";
+
+/// User of the session, attached to requests by [`session`].
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct User {
+    pub(crate) id: String,
+    pub(crate) username: String,
+}
+
+/// Token of the session cookie, attached to requests by [`session`] along with its [`User`].
+#[derive(Debug, Clone)]
+pub(crate) struct Session(pub(crate) String);
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Credentials {
+    username: String,
+    password: String,
+}
+
+pub(crate) fn router() -> Router<AppState> {
+    Router::new()
+        .route("/login", get(login_form).post(login))
+        .route("/logout", post(logout))
+        .route("/me", get(me))
+}
+
+fn cookie(token: &str, max_age: Duration) -> String {
+    format!(
+        "{COOKIE}={token}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+        max_age.as_secs()
+    )
+}
+
+/// Attach the [`User`] of the session cookie, if any, to the request.
+pub(crate) async fn session(
+    State(AppState { pool, .. }): State<AppState>,
+    cookies: Option<TypedHeader<Cookie>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if let Some(token) = cookies.as_ref().and_then(|c| c.get(COOKIE)) {
+        let conn = match pool.get().await.map_err(internal_error) {
+            Ok(conn) => conn,
+            Err(err) => return err.into_response(),
+        };
+        let user = match conn
+            .query_opt(
+                "select id::text, username from users where id = tankard_session_user($1);",
+                &[&token],
+            )
+            .await
+        {
+            Ok(user) => user,
+            Err(err) => return internal_error(err).into_response(),
+        };
+        if let Some(row) = user {
+            request.extensions_mut().insert(User {
+                id: row.get(0),
+                username: row.get(1),
+            });
+            request.extensions_mut().insert(Session(token.to_string()));
+        }
+    }
+    next.run(request).await
+}
+
+/// The login form, or just the form with `error` for htmx to swap in.
+async fn render(
+    state: &AppState,
+    csrf: &CsrfToken,
+    fragment: bool,
+    error: Option<&str>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    let conn = state.pool.get().await.map_err(internal_error)?;
+    let (template, context) = (
+        include_str!("../../tmpl/login.html"),
+        json!({ "error": error, "csrf": csrf.0 }),
+    );
+    if fragment {
+        conn.query_one(
+            "select html_minify(jinja_render($1, $2));",
+            &[&template, &context],
+        )
+        .await
+    } else {
+        conn.query_one(
+            "select html_minify(html(jinja_render($1, $2), $3));",
+            &[&template, &context, &csrf.0],
+        )
+        .await
+    }
+    .map(|row| Html(row.get(0)))
+    .map_err(internal_error)
+}
+
+async fn login_form(
+    State(state): State<AppState>,
+    Extension(csrf): Extension<CsrfToken>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    render(&state, &csrf, false, None).await
+}
+
+/// Start a session for the credentials, posted as a form or as JSON.
+async fn login(
+    State(state): State<AppState>,
+    Extension(csrf): Extension<CsrfToken>,
+    HxRequest(htmx): HxRequest,
+    headers: HeaderMap,
+    JsonOrForm(Credentials { username, password }): JsonOrForm<Credentials>,
+) -> Response {
+    let json = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+
+    let conn = match state.pool.get().await.map_err(internal_error) {
+        Ok(conn) => conn,
+        Err(err) => return err.into_response(),
+    };
+    // unknown users are verified against a dummy hash, so they take as long as wrong passwords
+    let user = match conn
+        .query_one(
+            "select users.id::text, users.username, tankard_password_needs_rehash(users.passhash), tankard_verify_password($2, coalesce(users.passhash, $3)) from (select) one left join users on users.username = $1;",
+            &[&username, &password, &DUMMY_HASH],
+        )
+        .await
+    {
+        Ok(row) => Some(row).filter(|row| row.get::<_, bool>(3)),
+        Err(err) => return internal_error(err).into_response(),
+    };
+    let Some(row) = user else {
+        let error = "invalid username or password";
+        return match (json, htmx) {
+            (true, _) => (StatusCode::UNAUTHORIZED, error.to_string()).into_response(),
+            // htmx only swaps in successful responses
+            (false, true) => render(&state, &csrf, true, Some(error))
+                .await
+                .into_response(),
+            (false, false) => match render(&state, &csrf, false, Some(error)).await {
+                Ok(html) => (StatusCode::UNAUTHORIZED, html).into_response(),
+                Err(err) => err.into_response(),
+            },
+        };
+    };
+    let user = User {
+        id: row.get(0),
+        username: row.get(1),
+    };
+
+    // hashes made with other costs, or migrated ones, are upgraded while the password is at hand
+    if row.get::<_, bool>(2) {
+        if let Err(err) = conn
+            .execute(
+                "update users set passhash = tankard_hash_password($2) where id = $1::text::uuid;",
+                &[&user.id, &password],
+            )
+            .await
+        {
+            return internal_error(err).into_response();
+        }
+    }
+    let token = match conn
+        .query_one(
+            "select tankard_session_start($1::text::uuid, make_interval(secs => $2));",
+            &[&user.id, &SESSION_TTL.as_secs_f64()],
+        )
+        .await
+    {
+        Ok(row) => row.get::<_, String>(0),
+        Err(err) => return internal_error(err).into_response(),
+    };
+    let set_cookie = [(SET_COOKIE, cookie(&token, SESSION_TTL))];
+
+    match (json, htmx) {
+        (true, _) => (set_cookie, Json(user)).into_response(),
+        (false, true) => (set_cookie, HxRedirect(Uri::from_static("/")), ()).into_response(),
+        (false, false) => (set_cookie, Redirect::to("/")).into_response(),
+    }
+}
+
+/// End the session and clear its cookie.
+async fn logout(
+    State(AppState { pool, .. }): State<AppState>,
+    HxRequest(htmx): HxRequest,
+    cookies: Option<TypedHeader<Cookie>>,
+) -> Response {
+    if let Some(token) = cookies.as_ref().and_then(|c| c.get(COOKIE)) {
+        let conn = match pool.get().await.map_err(internal_error) {
+            Ok(conn) => conn,
+            Err(err) => return err.into_response(),
+        };
+        if let Err(err) = conn
+            .execute("select tankard_session_end($1);", &[&token])
+            .await
+        {
+            return internal_error(err).into_response();
+        }
+    }
+    let set_cookie = [(SET_COOKIE, cookie("", Duration::ZERO))];
+
+    if htmx {
+        (set_cookie, HxRedirect(Uri::from_static("/auth/login")), ()).into_response()
+    } else {
+        (StatusCode::NO_CONTENT, set_cookie).into_response()
+    }
+}
+
+async fn me(user: Option<Extension<User>>) -> Response {
+    match user {
+        Some(Extension(user)) => Json(user).into_response(),
+        None => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use axum::{
+        body::Body,
+        extract::Request,
+        http::{
+            header::{CONTENT_TYPE, COOKIE, LOCATION, SET_COOKIE},
+            StatusCode,
+        },
+        Router,
+    };
+    use http_body_util::BodyExt;
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use crate::tests::setup_app;
+
+    async fn me(app: &Router, cookie: &str) -> Result<(StatusCode, Value), Box<dyn Error>> {
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/auth/me")
+                    .header(COOKIE, cookie)
+                    .body(Body::empty())?,
+            )
+            .await?;
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+        Ok((status, serde_json::from_slice(&body).unwrap_or_default()))
+    }
+
+    #[tokio::test]
+    async fn users_login() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("5e1b9c3a-7d2f-4a68-b0e4-2c8f6a1d9b73").await?;
+        conn.execute(
+            "insert into users (username, salt, passhash) values ('one', '', tankard_hash_password('hunter2'));",
+            &[],
+        )
+        .await?;
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/auth/login")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(
+                        json!({ "username": "one", "password": "hunter3" }).to_string(),
+                    ))?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(response.headers().get(SET_COOKIE).is_none());
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/auth/login")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(
+                        json!({ "username": "two", "password": "hunter2" }).to_string(),
+                    ))?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/auth/login")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(
+                        json!({ "username": "one", "password": "hunter2" }).to_string(),
+                    ))?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let set_cookie = response.headers()[SET_COOKIE].to_str()?.to_string();
+        assert!(set_cookie.contains("HttpOnly; Secure; SameSite=Lax"));
+        let cookie = set_cookie.split(';').next().unwrap_or_default();
+
+        assert_eq!(me(&app, cookie).await?.1["username"], "one");
+        assert_eq!(
+            me(&app, "tankard_session=nope").await?.0,
+            StatusCode::UNAUTHORIZED
+        );
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/auth/logout")
+                    .header(COOKIE, cookie)
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(response.headers()[SET_COOKIE]
+            .to_str()?
+            .contains("Max-Age=0"));
+        assert_eq!(me(&app, cookie).await?.0, StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_login_form() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("b3d7f1e9-2a6c-4e85-9f0b-7c4a1e8d5b26").await?;
+        conn.execute(
+            "insert into users (username, salt, passhash) values ('one', '', tankard_hash_password('hunter2'));",
+            &[],
+        )
+        .await?;
+
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri("/auth/login").body(Body::empty())?)
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let cookie = response.headers()[SET_COOKIE].to_str()?;
+        let cookie = cookie.split(';').next().unwrap_or_default().to_string();
+        let token = cookie.trim_start_matches("tankard_csrf=");
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/auth/login")
+                    .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                    .header(COOKIE, &cookie)
+                    .body(Body::from(format!(
+                        "username=one&password=hunter2&_csrf={token}"
+                    )))?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(response.headers()[LOCATION], "/");
+        assert!(response.headers().contains_key(SET_COOKIE));
+
+        Ok(())
+    }
+}
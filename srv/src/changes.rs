@@ -0,0 +1,506 @@
+use std::{convert::Infallible, fmt, sync::Arc, time::Duration};
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{sse::Event, Sse},
+};
+use bb8_postgres::PostgresConnectionManager;
+use futures::{stream, FutureExt, Stream, StreamExt};
+use itertools::Itertools;
+use serde_json::{Map, Value};
+use tokio_postgres::NoTls;
+
+use crate::{
+    api::{Column, Context, Filters, Select, Table, Transaction},
+    hub::Subscription,
+    internal_error, sse, unavailable, AppState,
+};
+
+/// Channel the `tankard_change_notify` trigger notifies on.
+pub(crate) const CHANNEL: &str = "tankard_changes";
+const PRUNE: Duration = Duration::from_secs(60);
+/// Outbox rows read at a time.
+const PAGE: i64 = 1000;
+/// How often changes held back behind older transactions are read again.
+pub(crate) const POLL: Duration = Duration::from_secs(1);
+
+/// What a subscriber of a table's changes asked for, and who they are.
+pub(crate) struct Feed {
+    table: String,
+    columns: Vec<Column>,
+    select: String,
+    filters: Filters,
+    context: Context,
+}
+
+impl Feed {
+    pub(crate) fn new(
+        table: String,
+        columns: Vec<Column>,
+        select: &[String],
+        filters: Filters,
+        context: Context,
+    ) -> Self {
+        // like `get_table`, hidden columns are left out unless selected and masks applied
+        let select = format!(
+            "jsonb_build_object({})",
+            columns
+                .iter()
+                .filter(|c| {
+                    if select.is_empty() {
+                        !c.hidden
+                    } else {
+                        select.contains(&c.column_name)
+                    }
+                })
+                .map(|c| format!("'{}', {}", c.column_name, c.masked(&c.column_name)))
+                .join(",")
+        );
+        Self {
+            table,
+            columns,
+            select,
+            filters,
+            context,
+        }
+    }
+
+    /// Apply `select` and the filters to a row of the payload, `null` when it is filtered out or
+    /// the role could not have seen it.
+    async fn project(&self, conn: &Transaction, row: Option<&Value>) -> Option<Value> {
+        let Some(row) = row.filter(|row| !row.is_null()) else {
+            return Some(Value::Null);
+        };
+        let Self { table, select, .. } = self;
+        let condition = self.filters.condition(table, &self.columns);
+        conn.query_opt(
+            &format!(
+                "select {select} from jsonb_populate_record(null::{table}, $1) {table} where tankard_visible('{table}', $1) and {condition};"
+            ),
+            &[row],
+        )
+        .await
+        .ok()
+        .map(|row| row.map_or(Value::Null, |row| row.get(0)))
+    }
+
+    /// The row of primary key `pk` as it is now, `None` when the role may not see it, or else
+    /// `select` of it, `null` when it is filtered out.
+    async fn current(&self, conn: &Transaction, pk: &Map<String, Value>) -> Option<Value> {
+        let Self { table, select, .. } = self;
+        let keys = pk
+            .keys()
+            .filter(|key| self.columns.iter().any(|c| &c.column_name == *key))
+            .join(",");
+        if keys.is_empty() {
+            return None;
+        }
+        let condition = self.filters.condition(table, &self.columns);
+        let row = conn
+            .query_opt(
+                &format!(
+                    "select {select}, {condition} from (select * from {table} where ({keys}) = (select {keys} from jsonb_populate_record(null::{table}, $1))) {table};"
+                ),
+                &[&Value::Object(pk.clone())],
+            )
+            .await
+            .ok()??;
+        Some(if row.get::<_, bool>(1) {
+            row.get(0)
+        } else {
+            Value::Null
+        })
+    }
+
+    /// The change with `select` and the filters applied to the rows it captured, `None` when
+    /// both are filtered out.
+    pub(crate) async fn captured(
+        &self,
+        conn: &Transaction,
+        (id, mut change): Change,
+    ) -> Option<Change> {
+        let old = self.project(conn, change.get("old")).await?;
+        let new = self.project(conn, change.get("new")).await?;
+        if old.is_null() && new.is_null() {
+            return None;
+        }
+        change.insert("old".to_string(), old);
+        change.insert("new".to_string(), new);
+        Some((id, change))
+    }
+
+    /// The change as the subscriber sees it, `None` when none of it is theirs to see.
+    ///
+    /// Inserted and updated rows are read again as the role of the subscriber, with `select` and
+    /// the filters applied, so row-level security and grants hold for live changes too. Deleted
+    /// rows cannot be read again, their events carry the row as it was, when the role could have
+    /// seen it.
+    pub(crate) async fn change(
+        &self,
+        state: &AppState,
+        (id, mut change): Change,
+    ) -> Option<Change> {
+        if change.get("table")?.as_str()? != self.table {
+            return None;
+        }
+        // payloads too large to notify only carry the key, the outbox has the rest
+        if !change.contains_key("new") {
+            change = state
+                .pool
+                .get()
+                .await
+                .ok()?
+                .query_one("select payload from tankard_outbox where id = $1;", &[&id])
+                .await
+                .ok()
+                .and_then(|row| serde_json::from_value(row.get(0)).ok())?;
+        }
+
+        let conn = Transaction::begin(state.pool, &self.context).await.ok()?;
+        if change.get("op")?.as_str()? == "delete" {
+            return self.captured(&conn, (id, change)).await;
+        }
+        let new = self.current(&conn, change.get("pk")?.as_object()?).await?;
+        let old = self.project(&conn, change.get("old")).await?;
+        if old.is_null() && new.is_null() {
+            return None;
+        }
+        change.insert("old".to_string(), old);
+        change.insert("new".to_string(), new);
+        Some((id, change))
+    }
+}
+
+/// Outbox id and payload of a change.
+pub(crate) type Change = (i64, Map<String, Value>);
+
+/// Parse a notification of [`CHANNEL`].
+pub(crate) fn change(payload: &str) -> Option<Change> {
+    let mut change = serde_json::from_str::<Map<String, Value>>(payload).ok()?;
+    let id = change.remove("id")?.as_i64()?;
+    Some((id, change))
+}
+
+/// Delete outbox entries past `tankard.outbox_retention` every so often.
+pub(crate) async fn prune(pool: &'static bb8::Pool<PostgresConnectionManager<NoTls>>) {
+    let mut interval = tokio::time::interval(PRUNE);
+    loop {
+        interval.tick().await;
+        if let Ok(conn) = pool.get().await {
+            _ = conn.execute("select tankard_outbox_prune();", &[]).await;
+        }
+    }
+}
+
+/// Position in the outbox, the transaction and id of the last change read, `xid:id` as an
+/// event id.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Cursor {
+    pub(crate) xid: i64,
+    pub(crate) id: i64,
+}
+
+impl Cursor {
+    fn parse(s: &str) -> Option<Self> {
+        let (xid, id) = s.split_once(':')?;
+        Some(Self {
+            xid: xid.parse().ok()?,
+            id: id.parse().ok()?,
+        })
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.xid, self.id)
+    }
+}
+
+/// Changes of `table`, or of every table, after `cursor`, and whether more are held back.
+///
+/// Outbox ids are taken on insert, so a transaction committing late can leave a lower id behind
+/// ones already read. Changes are read by transaction instead, and only those of transactions
+/// older than every one still running, so none can turn up behind the cursor later.
+pub(crate) async fn read(
+    conn: &tokio_postgres::Client,
+    table: Option<&str>,
+    cursor: Cursor,
+) -> Result<(Vec<(Cursor, Change)>, bool), (StatusCode, String)> {
+    let rows = conn
+        .query(
+            "select xid::text::bigint, id, payload, xid < pg_snapshot_xmin(pg_current_snapshot())
+             from tankard_outbox
+             where (xid, id) > ($1::bigint::text::xid8, $2) and ($3::text is null or payload ->> 'table' = $3)
+             order by xid, id
+             limit $4;",
+            &[&cursor.xid, &cursor.id, &table, &PAGE],
+        )
+        .await
+        .map_err(internal_error)?;
+    let held = rows.iter().any(|row| !row.get::<_, bool>(3));
+    let changes = rows
+        .into_iter()
+        .take_while(|row| row.get::<_, bool>(3))
+        .filter_map(|row| {
+            let (xid, id) = (row.get(0), row.get(1));
+            Some((
+                Cursor { xid, id },
+                (id, serde_json::from_value(row.get(2)).ok()?),
+            ))
+        })
+        .collect();
+    Ok((changes, held))
+}
+
+/// Changes of `table` after `cursor` from the outbox, read again whenever a change is notified.
+fn outbox(
+    pool: &'static bb8::Pool<PostgresConnectionManager<NoTls>>,
+    table: String,
+    cursor: Cursor,
+    notifications: Subscription,
+) -> impl Stream<Item = (Cursor, Change)> {
+    stream::unfold(
+        (cursor, notifications),
+        move |(mut cursor, mut notifications)| {
+            let table = table.clone();
+            async move {
+                loop {
+                    let conn = pool.get().await.ok()?;
+                    let (changes, held) = read(&conn, Some(&table), cursor).await.ok()?;
+                    drop(conn);
+                    if let Some((last, _)) = changes.last() {
+                        cursor = *last;
+                        return Some((changes, (cursor, notifications)));
+                    }
+                    // changes held back behind older transactions are not notified again
+                    tokio::select! {
+                        notification = notifications.next() => {
+                            notification?;
+                        }
+                        _ = tokio::time::sleep(POLL), if held => {}
+                    }
+                    while let Some(Some(_)) = notifications.next().now_or_never() {}
+                }
+            }
+        },
+    )
+    .flat_map(stream::iter)
+}
+
+/// Stream row changes of a table, with `select` and filters applied to the changed rows.
+///
+/// Clients reconnecting with `Last-Event-ID` get the changes they missed from the outbox first.
+pub(crate) async fn changes(
+    Table {
+        name: table,
+        columns,
+    }: Table,
+    Select(select): Select,
+    filters: Filters,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    context: Context,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    let conn = Transaction::begin(state.pool, &context).await?;
+    let select = state
+        .privileges
+        .authorize(&conn, &table, &columns, select, &filters)
+        .await?;
+    drop(conn);
+
+    // subscribe before reading the outbox, so nothing falls in between
+    let notifications = state
+        .hub
+        .subscribe(state.changes)
+        .await
+        .map_err(unavailable)?;
+    // the outbox is only there when changes come from the triggers
+    let changes = if state.changes == CHANNEL {
+        let last_id = headers
+            .get("last-event-id")
+            .and_then(|id| id.to_str().ok())
+            .and_then(Cursor::parse);
+        let cursor = match last_id {
+            Some(cursor) => cursor,
+            // anything of transactions still running or to come
+            None => state
+                .pool
+                .get()
+                .await
+                .map_err(internal_error)?
+                .query_one(
+                    "select pg_snapshot_xmin(pg_current_snapshot())::text::bigint - 1;",
+                    &[],
+                )
+                .await
+                .map(|row| Cursor {
+                    xid: row.get(0),
+                    id: i64::MAX,
+                })
+                .map_err(internal_error)?,
+        };
+        outbox(state.pool, table.clone(), cursor, notifications)
+            .map(|(cursor, change)| (cursor.to_string(), change))
+            .boxed()
+    } else {
+        notifications
+            .filter_map(|n| async move { change(&n.payload) })
+            .map(|change| (change.0.to_string(), change))
+            .boxed()
+    };
+
+    let feed = Arc::new(Feed::new(table, columns, &select, filters, context));
+    let hub = state.hub.clone();
+    Ok(sse(
+        hub,
+        changes.filter_map(move |(event_id, change)| {
+            let (feed, state) = (feed.clone(), state.clone());
+            async move {
+                let (_, change) = feed.change(&state, change).await?;
+                Event::default()
+                    .id(event_id)
+                    .event(change.get("op")?.as_str()?)
+                    .json_data(change)
+                    .ok()
+            }
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use axum::{body::Body, extract::Request, http::StatusCode};
+    use futures::StreamExt;
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    use crate::tests::{setup_app, setup_pool};
+
+    /// Parse the `data` of an SSE event.
+    fn data(event: &[u8]) -> Result<serde_json::Value, Box<dyn Error>> {
+        let data = std::str::from_utf8(event)?
+            .lines()
+            .find_map(|line| line.strip_prefix("data: "))
+            .ok_or("no data")?;
+        Ok(serde_json::from_str(data)?)
+    }
+
+    #[tokio::test]
+    async fn users_changes() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("5b1d7e3a-9c2f-4e8b-a6d4-0f3e2c1b9a87").await?;
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users/changes?select=username&email=eq.foo")
+                    .header("Accept", "*/*")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        conn.batch_execute(
+            "insert into users (username, salt, passhash, email) values ('one', '', '', 'bar'); insert into users (username, salt, passhash, email) values ('two', '', '', 'foo');",
+        )
+        .await?;
+
+        let event = response
+            .into_body()
+            .into_data_stream()
+            .next()
+            .await
+            .unwrap()?;
+        let change = data(&event)?;
+
+        assert!(std::str::from_utf8(&event)?.contains("event: insert\n"));
+        assert_eq!(change["table"], "users");
+        assert_eq!(change["old"], json!(null));
+        assert_eq!(change["new"], json!({ "username": "two" }));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_changes_resume() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("e0a4c7d2-3b5f-4a19-8c6e-2d7f1b9e4a53").await?;
+
+        conn.batch_execute(
+            "insert into users (username, salt, passhash, email) values ('one', '', '', 'foo'); insert into users (username, salt, passhash, email) values ('two', '', '', 'foo');",
+        )
+        .await?;
+        let ids = conn
+            .query(
+                "select xid::text || ':' || id from tankard_outbox where payload ->> 'table' = 'users' order by xid, id;",
+                &[],
+            )
+            .await?
+            .iter()
+            .map(|row| row.get::<_, String>(0))
+            .collect::<Vec<_>>();
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users/changes?select=username")
+                    .header("Accept", "*/*")
+                    .header("Last-Event-ID", &ids[0])
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let event = response
+            .into_body()
+            .into_data_stream()
+            .next()
+            .await
+            .unwrap()?;
+
+        assert!(std::str::from_utf8(&event)?.contains(&format!("id: {}\n", ids[1])));
+        assert_eq!(data(&event)?["new"], json!({ "username": "two" }));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_changes_late_commit() -> Result<(), Box<dyn Error>> {
+        let db_name = "7f3c9a2e-1d5b-4e86-b0a4-c2e8f6d1b937";
+        let (conn, app) = setup_app(db_name).await?;
+        let (pool, _) = setup_pool(db_name).await?;
+        let late = pool.get().await?;
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/api/users/changes?select=username")
+                    .header("Accept", "*/*")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // the first insert takes the lower outbox id, but commits last
+        late.batch_execute(
+            "begin; insert into users (username, salt, passhash) values ('one', '', '');",
+        )
+        .await?;
+        conn.batch_execute("insert into users (username, salt, passhash) values ('two', '', '');")
+            .await?;
+        late.batch_execute("commit;").await?;
+
+        let mut events = response.into_body().into_data_stream();
+        let one = events.next().await.unwrap()?;
+        let two = events.next().await.unwrap()?;
+
+        assert_eq!(data(&one)?["new"], json!({ "username": "one" }));
+        assert_eq!(data(&two)?["new"], json!({ "username": "two" }));
+
+        Ok(())
+    }
+}
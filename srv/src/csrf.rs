@@ -0,0 +1,279 @@
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::{FromRequest, Multipart, Request},
+    http::{
+        header::{CONTENT_TYPE, SET_COOKIE},
+        HeaderValue, Method, StatusCode,
+    },
+    middleware::Next,
+    response::{Html, IntoResponse, Response},
+};
+use axum_extra::{headers::Cookie, TypedHeader};
+
+/// Name of the cookie holding the token.
+const COOKIE: &str = "tankard_csrf";
+/// Header htmx sends the token in, see `hx-headers` of `html()`.
+const HEADER: &str = "x-csrf-token";
+/// Form field plain forms send the token in.
+const FIELD: &str = "_csrf";
+/// Largest form body read to find [`FIELD`], like the limit of `Form`.
+const FORM_LIMIT: usize = 2 * 1024 * 1024;
+
+/// CSRF token of the client, attached to requests by [`protect`] for rendering into pages.
+#[derive(Debug, Clone)]
+pub(crate) struct CsrfToken(pub(crate) String);
+
+fn token() -> String {
+    let mut bytes = [0; 32];
+    getrandom::getrandom(&mut bytes).expect("no source of randomness");
+    hex::encode(bytes)
+}
+
+fn cookie(token: &str) -> String {
+    format!("{COOKIE}={token}; Path=/; HttpOnly; Secure; SameSite=Strict")
+}
+
+/// Compare without leaking how much of the tokens match through timing.
+fn matches(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+fn reject() -> Response {
+    (
+        StatusCode::FORBIDDEN,
+        Html(r#"<p role="alert">The form has expired, reload the page and try again.</p>"#),
+    )
+        .into_response()
+}
+
+/// The value of [`FIELD`] in a form body of `content_type`.
+async fn field(content_type: &str, bytes: Bytes) -> Option<String> {
+    if content_type.starts_with("multipart/form-data") {
+        let request = Request::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(bytes))
+            .ok()?;
+        let mut multipart = Multipart::from_request(request, &()).await.ok()?;
+        while let Some(field) = multipart.next_field().await.ok()? {
+            if field.name() == Some(FIELD) {
+                return field.text().await.ok();
+            }
+        }
+        None
+    } else if content_type.starts_with("text/plain") {
+        // `name=value` lines, unencoded
+        std::str::from_utf8(&bytes)
+            .ok()?
+            .lines()
+            .find_map(|line| line.strip_prefix(FIELD)?.strip_prefix('='))
+            .map(str::to_string)
+    } else {
+        serde_urlencoded::from_bytes::<Vec<(String, String)>>(&bytes)
+            .ok()?
+            .into_iter()
+            .find_map(|(name, value)| (name == FIELD).then_some(value))
+    }
+}
+
+/// Double-submit cookie protection: form posts, which browsers send across sites, must carry
+/// the token of the cookie in [`HEADER`] or [`FIELD`]. Clients without a cookie are given one
+/// with their next `GET`.
+pub(crate) async fn protect(
+    cookies: Option<TypedHeader<Cookie>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let cookie_token = cookies
+        .as_ref()
+        .and_then(|c| c.get(COOKIE))
+        .map(str::to_string);
+
+    let content_type = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let form = content_type.starts_with("application/x-www-form-urlencoded")
+        || content_type.starts_with("multipart/form-data")
+        || content_type.starts_with("text/plain");
+    let safe = matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    );
+    if form && !safe {
+        let Some(expected) = &cookie_token else {
+            return reject();
+        };
+        let header = request
+            .headers()
+            .get(HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let submitted = match header {
+            Some(header) => header,
+            None => {
+                let (parts, body) = request.into_parts();
+                let Ok(bytes) = to_bytes(body, FORM_LIMIT).await else {
+                    return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+                };
+                let field = field(&content_type, bytes.clone()).await;
+                request = Request::from_parts(parts, Body::from(bytes));
+                field.unwrap_or_default()
+            }
+        };
+        if !matches(expected, &submitted) {
+            return reject();
+        }
+    }
+
+    // pages are rendered by safe requests, so that is where the cookie is handed out
+    let (token, issued) = match cookie_token {
+        Some(token) => (token, false),
+        None => (token(), safe),
+    };
+    request.extensions_mut().insert(CsrfToken(token.clone()));
+    let mut response = next.run(request).await;
+    if issued {
+        if let Ok(cookie) = HeaderValue::from_str(&cookie(&token)) {
+            response.headers_mut().append(SET_COOKIE, cookie);
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use axum::{
+        body::Body,
+        extract::Request,
+        http::{
+            header::{CONTENT_TYPE, COOKIE, SET_COOKIE},
+            StatusCode,
+        },
+    };
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    use crate::tests::setup_app;
+
+    #[tokio::test]
+    async fn users_login_csrf() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("d4f7a2c8-5e1b-4c39-8a6d-0b9e3f7c1a52").await?;
+        conn.execute(
+            "insert into users (username, salt, passhash) values ('one', '', tankard_hash_password('hunter2'));",
+            &[],
+        )
+        .await?;
+
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri("/auth/login").body(Body::empty())?)
+            .await?;
+
+        let set_cookie = response.headers()[SET_COOKIE].to_str()?.to_string();
+        assert!(set_cookie.starts_with("tankard_csrf="));
+        let cookie = set_cookie.split(';').next().unwrap_or_default().to_string();
+        let token = cookie.trim_start_matches("tankard_csrf=").to_string();
+
+        let login = |body: String, headers: &[(&str, &str)]| {
+            let mut request = Request::builder()
+                .method("POST")
+                .uri("/auth/login")
+                .header(CONTENT_TYPE, "application/x-www-form-urlencoded");
+            for (name, value) in headers {
+                request = request.header(*name, *value);
+            }
+            app.clone().oneshot(request.body(Body::from(body)).unwrap())
+        };
+
+        let response = login("username=one&password=hunter2".to_string(), &[]).await?;
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = login(
+            "username=one&password=hunter2&_csrf=nope".to_string(),
+            &[(COOKIE.as_str(), &cookie)],
+        )
+        .await?;
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(
+            String::from_utf8(response.into_body().collect().await?.to_bytes().into())?
+                .contains("role=\"alert\"")
+        );
+
+        let response = login(
+            format!("username=one&password=hunter2&_csrf={token}"),
+            &[(COOKIE.as_str(), &cookie)],
+        )
+        .await?;
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        let response = login(
+            "username=one&password=hunter2".to_string(),
+            &[(COOKIE.as_str(), &cookie), ("x-csrf-token", &token)],
+        )
+        .await?;
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_login_csrf_field() -> Result<(), Box<dyn Error>> {
+        let (_conn, app) = setup_app("7b3e9c1d-2f6a-4d85-9e0b-5c8a1f4d2e63").await?;
+
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri("/auth/login").body(Body::empty())?)
+            .await?;
+
+        let set_cookie = response.headers()[SET_COOKIE].to_str()?.to_string();
+        let cookie = set_cookie.split(';').next().unwrap_or_default().to_string();
+        let token = cookie.trim_start_matches("tankard_csrf=").to_string();
+
+        let post = |content_type: &str, body: String| {
+            let request = Request::builder()
+                .method("POST")
+                .uri("/auth/login")
+                .header(CONTENT_TYPE, content_type)
+                .header(COOKIE, &cookie);
+            app.clone().oneshot(request.body(Body::from(body)).unwrap())
+        };
+        let multipart = |csrf: &str| {
+            format!(
+                "--boundary\r\nContent-Disposition: form-data; name=\"username\"\r\n\r\none\r\n\
+                 --boundary\r\nContent-Disposition: form-data; name=\"_csrf\"\r\n\r\n{csrf}\r\n\
+                 --boundary--\r\n"
+            )
+        };
+
+        let response = post("multipart/form-data; boundary=boundary", multipart("nope")).await?;
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // the login form takes no multipart, but the token got past the middleware
+        let response = post("multipart/form-data; boundary=boundary", multipart(&token)).await?;
+
+        assert_ne!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = post("text/plain", "username=one\r\n_csrf=nope\r\n".to_string()).await?;
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = post("text/plain", format!("username=one\r\n_csrf={token}\r\n")).await?;
+
+        assert_ne!(response.status(), StatusCode::FORBIDDEN);
+
+        Ok(())
+    }
+}
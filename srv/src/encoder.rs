@@ -0,0 +1,639 @@
+use std::fmt;
+
+use axum::{
+    async_trait,
+    body::{Body, Bytes},
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::{Html, IntoResponse, Response},
+};
+use axum_extra::extract::JsonLines;
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use headers_accept::Accept;
+use itertools::Itertools;
+use mediatype::{media_type, names, MediaType, Name};
+use serde::{ser::SerializeMap, Serialize};
+use tokio_postgres::Client;
+
+use crate::{
+    api::{Column, CsvDialect, Filters},
+    internal_error,
+    spreadsheet::{self, Cell},
+};
+
+pub(crate) static MT_TEXT_HTML: MediaType = media_type!(TEXT / HTML);
+pub(crate) static MT_APPLICATION_JSON: MediaType = media_type!(APPLICATION / JSON);
+pub(crate) static MT_TEXT_CSV: MediaType = media_type!(TEXT / CSV);
+pub(crate) static MT_XLSX: MediaType = MediaType::new(
+    names::APPLICATION,
+    Name::new_unchecked("vnd.openxmlformats-officedocument.spreadsheetml.sheet"),
+);
+pub(crate) static MT_ODS: MediaType = MediaType::new(
+    names::APPLICATION,
+    Name::new_unchecked("vnd.oasis.opendocument.spreadsheet"),
+);
+pub(crate) static MT_COPY_BINARY: MediaType = MediaType::new(
+    names::APPLICATION,
+    Name::new_unchecked("vnd.postgresql.copy-binary"),
+);
+pub(crate) static MT_APPLICATION_XML: MediaType = media_type!(APPLICATION / XML);
+pub(crate) static MT_APPLICATION_YAML: MediaType =
+    MediaType::new(names::APPLICATION, Name::new_unchecked("yaml"));
+pub(crate) static MT_APPLICATION_MSGPACK: MediaType =
+    MediaType::new(names::APPLICATION, Name::new_unchecked("msgpack"));
+pub(crate) static MT_APPLICATION_CBOR: MediaType = media_type!(APPLICATION / CBOR);
+pub(crate) static MT_APPLICATION_GEO_JSON: MediaType = MediaType::from_parts(
+    names::APPLICATION,
+    Name::new_unchecked("geo"),
+    Some(names::JSON),
+    &[],
+);
+
+/// What `get_table` was asked to read.
+pub(crate) struct Selection<'a> {
+    pub(crate) table: &'a str,
+    pub(crate) columns: &'a [Column],
+    pub(crate) select: &'a [String],
+    pub(crate) filters: &'a Filters,
+    pub(crate) csv: &'a CsvDialect,
+}
+
+impl<'a> Selection<'a> {
+    /// Selected columns, or every column of the table but hidden ones when nothing is selected.
+    fn head(&self) -> Vec<&'a Column> {
+        if !self.select.is_empty() {
+            self.select
+                .iter()
+                .filter_map(|s| self.columns.iter().find(|c| &c.column_name == s))
+                .collect()
+        } else {
+            self.columns.iter().filter(|c| !c.hidden).collect()
+        }
+    }
+
+    /// `where` clause of the filters.
+    fn clause(&self) -> String {
+        self.filters.clause(self.table, self.columns)
+    }
+
+    /// Select list for `COPY` based encoders, with masks applied.
+    fn list(&self) -> String {
+        self.head().into_iter().map(Column::select).join(",")
+    }
+}
+
+/// Turns the rows of a table into a response body of one media type.
+#[async_trait]
+pub(crate) trait Encoder: Send + Sync {
+    fn media_type(&self) -> &'static MediaType<'static>;
+
+    /// Name used to pick this encoder with `?format=` or a path extension.
+    fn extension(&self) -> &'static str;
+
+    /// Whether an explicitly picked format is served as a file download.
+    fn attachment(&self) -> bool {
+        true
+    }
+
+    async fn encode(&self, conn: &Client, selection: &Selection<'_>) -> Response;
+}
+
+/// Encoders available to `get_table`, in order of preference.
+pub(crate) struct Encoders(Vec<Box<dyn Encoder>>);
+
+impl Encoders {
+    pub(crate) fn register(&mut self, encoder: impl Encoder + 'static) -> &mut Self {
+        self.0.push(Box::new(encoder));
+        self
+    }
+
+    pub(crate) fn negotiate(&self, accept: &Accept) -> Option<&dyn Encoder> {
+        let mt = accept.negotiate(self.0.iter().map(|e| e.media_type()))?;
+        self.0
+            .iter()
+            .find(|e| e.media_type() == mt)
+            .map(AsRef::as_ref)
+    }
+
+    pub(crate) fn by_extension(&self, extension: &str) -> Option<&dyn Encoder> {
+        self.0
+            .iter()
+            .find(|e| e.extension() == extension)
+            .map(AsRef::as_ref)
+    }
+}
+
+impl fmt::Debug for Encoders {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|e| e.extension()))
+            .finish()
+    }
+}
+
+impl Default for Encoders {
+    fn default() -> Self {
+        let mut encoders = Self(vec![]);
+        encoders
+            .register(HtmlEncoder)
+            .register(JsonEncoder)
+            .register(CsvEncoder)
+            .register(XlsxEncoder)
+            .register(OdsEncoder)
+            .register(CopyBinaryEncoder)
+            .register(XmlEncoder)
+            .register(YamlEncoder)
+            .register(MessagePackEncoder)
+            .register(CborEncoder)
+            .register(GeoJsonEncoder);
+        encoders
+    }
+}
+
+/// Stream the selected columns of every row as JSON values, in column order.
+async fn json_rows(
+    conn: &Client,
+    selection: &Selection<'_>,
+) -> Result<
+    (
+        Vec<String>,
+        impl Stream<Item = Result<Vec<serde_json::Value>, tokio_postgres::Error>>,
+    ),
+    tokio_postgres::Error,
+> {
+    let columns = selection.head();
+    let head = columns
+        .iter()
+        .map(|Column { column_name, .. }| column_name.clone())
+        .collect_vec();
+    let (table, clause) = (selection.table, selection.clause());
+    // a subquery rather than `json_build_array`, which takes at most 100 arguments
+    let statement = format!(
+        "select to_json(t) from (select {} from {table}{clause}) t;",
+        columns.iter().map(|c| c.select()).join(",")
+    );
+    let names = head.clone();
+    let rows = conn
+        .query_raw(&statement, std::iter::empty::<&str>())
+        .await?
+        .map_ok(move |row| {
+            let row = row.get::<_, serde_json::Value>(0);
+            names
+                .iter()
+                .map(|name| row.get(name).cloned().unwrap_or_default())
+                .collect()
+        });
+    Ok((head, rows))
+}
+
+/// A row serialized as a map of column name to value, keeping column order.
+struct Row<'a>(&'a [String], &'a [serde_json::Value]);
+
+impl Serialize for Row<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (column, value) in self.0.iter().zip(self.1) {
+            map.serialize_entry(column, value)?;
+        }
+        map.end()
+    }
+}
+
+/// Encode each row with `encode` and stream the chunks between `open` and `close`.
+async fn encode_rows<F, E>(
+    conn: &Client,
+    selection: &Selection<'_>,
+    mt: &MediaType<'_>,
+    open: Bytes,
+    close: Bytes,
+    encode: F,
+) -> Response
+where
+    F: Fn(Row) -> Result<Vec<u8>, E> + Send + 'static,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let (head, rows) = match json_rows(conn, selection).await.map_err(internal_error) {
+        Ok(rows) => rows,
+        Err(err) => return err.into_response(),
+    };
+    let body = rows.map_err(axum::Error::new).and_then(move |values| {
+        let chunk = encode(Row(&head, &values)).map_err(axum::Error::new);
+        async move { chunk.map(Bytes::from) }
+    });
+    let body = stream::once(async { Ok(open) })
+        .chain(body)
+        .chain(stream::once(async { Ok(close) }));
+
+    ([(CONTENT_TYPE, mt.to_string())], Body::from_stream(body)).into_response()
+}
+
+struct HtmlEncoder;
+
+#[async_trait]
+impl Encoder for HtmlEncoder {
+    fn media_type(&self) -> &'static MediaType<'static> {
+        &MT_TEXT_HTML
+    }
+
+    fn extension(&self) -> &'static str {
+        "html"
+    }
+
+    fn attachment(&self) -> bool {
+        false
+    }
+
+    async fn encode(&self, conn: &Client, selection: &Selection<'_>) -> Response {
+        let columns = selection.head();
+        let head = columns
+            .iter()
+            .map(|Column { column_name, .. }| format!("'{column_name}'"))
+            .join(",");
+        let select = columns
+            .iter()
+            .map(|c| format!("{}::text", c.masked(&c.column_name)))
+            .join(",");
+        let (table, clause) = (selection.table, selection.clause());
+
+        // TODO: support other keys than `id`
+        conn.query_one(&(format!(
+            "select html_minify(jinja_render($1, (select jsonb_build_object('head', array[{head}], 'body', (select array_agg(jsonb_build_object('key', id, 'cols', array[{select}])) from {table}{clause})))));"
+        )), &[&include_str!("../../tmpl/table.html")])
+        .await
+        .map(|row| Html(row.get::<_, String>(0)))
+        .map_err(internal_error)
+        .into_response()
+    }
+}
+
+struct JsonEncoder;
+
+#[async_trait]
+impl Encoder for JsonEncoder {
+    fn media_type(&self) -> &'static MediaType<'static> {
+        &MT_APPLICATION_JSON
+    }
+
+    fn extension(&self) -> &'static str {
+        "json"
+    }
+
+    async fn encode(&self, conn: &Client, selection: &Selection<'_>) -> Response {
+        let (table, clause) = (selection.table, selection.clause());
+        // a subquery rather than `json_build_object`, which takes at most 100 arguments
+        let select = selection.head().iter().map(|c| c.select()).join(",");
+        conn.copy_out(
+            &(format!(
+                "copy (select to_json(t) from (select {select} from {table}{clause}) t) to stdout;"
+            )),
+        )
+        .await
+        .map(|stream| {
+            JsonLines::new(
+                stream
+                    .map_ok(|b| serde_json::from_slice::<serde_json::Value>(&b))
+                    .filter_map(|res| async { res.ok() }),
+            )
+        })
+        .map_err(internal_error)
+        .into_response()
+    }
+}
+
+struct CsvEncoder;
+
+#[async_trait]
+impl Encoder for CsvEncoder {
+    fn media_type(&self) -> &'static MediaType<'static> {
+        &MT_TEXT_CSV
+    }
+
+    fn extension(&self) -> &'static str {
+        "csv"
+    }
+
+    async fn encode(&self, conn: &Client, selection: &Selection<'_>) -> Response {
+        let (table, select, clause) = (selection.table, selection.list(), selection.clause());
+        let options = match selection.csv.options() {
+            Ok(options) => options,
+            Err(err) => return err.into_response(),
+        };
+
+        conn.copy_out(
+            &(format!("copy (select {select} from {table}{clause}) to stdout with ({options});")),
+        )
+        .await
+        .map(Body::from_stream)
+        .map_err(internal_error)
+        .into_response()
+    }
+}
+
+struct CopyBinaryEncoder;
+
+#[async_trait]
+impl Encoder for CopyBinaryEncoder {
+    fn media_type(&self) -> &'static MediaType<'static> {
+        &MT_COPY_BINARY
+    }
+
+    fn extension(&self) -> &'static str {
+        "pgcopy"
+    }
+
+    async fn encode(&self, conn: &Client, selection: &Selection<'_>) -> Response {
+        let (table, select, clause) = (selection.table, selection.list(), selection.clause());
+
+        conn.copy_out(
+            &(format!(
+                "copy (select {select} from {table}{clause}) to stdout with (format binary);"
+            )),
+        )
+        .await
+        .map(|stream| {
+            (
+                [(CONTENT_TYPE, self.media_type().to_string())],
+                Body::from_stream(stream),
+            )
+        })
+        .map_err(internal_error)
+        .into_response()
+    }
+}
+
+/// Typed spreadsheet rows of the selection.
+async fn spreadsheet_rows<'a>(
+    conn: &Client,
+    selection: &Selection<'a>,
+) -> Result<(Vec<(&'a str, Cell)>, Vec<tokio_postgres::Row>), (StatusCode, String)> {
+    let columns = selection.head();
+    // masks turn values into text
+    let head = columns
+        .iter()
+        .map(|c| {
+            let cell = match c.mask {
+                None => Cell::of(&c.data_type),
+                Some(_) => Cell::Text,
+            };
+            (c.column_name.as_str(), cell)
+        })
+        .collect_vec();
+
+    let (table, select, clause) = (
+        selection.table,
+        columns
+            .iter()
+            .zip(&head)
+            .map(|(c, (_, cell))| cell.select(&c.masked(&c.column_name)))
+            .join(","),
+        selection.clause(),
+    );
+    let rows = conn
+        .query(&format!("select {select} from {table}{clause};"), &[])
+        .await
+        .map_err(internal_error)?;
+    Ok((head, rows))
+}
+
+struct XlsxEncoder;
+
+#[async_trait]
+impl Encoder for XlsxEncoder {
+    fn media_type(&self) -> &'static MediaType<'static> {
+        &MT_XLSX
+    }
+
+    fn extension(&self) -> &'static str {
+        "xlsx"
+    }
+
+    async fn encode(&self, conn: &Client, selection: &Selection<'_>) -> Response {
+        spreadsheet_rows(conn, selection)
+            .await
+            .and_then(|(head, rows)| {
+                spreadsheet::xlsx(selection.table, &head, &rows).map_err(internal_error)
+            })
+            .map(|body| ([(CONTENT_TYPE, self.media_type().to_string())], body))
+            .into_response()
+    }
+}
+
+struct OdsEncoder;
+
+#[async_trait]
+impl Encoder for OdsEncoder {
+    fn media_type(&self) -> &'static MediaType<'static> {
+        &MT_ODS
+    }
+
+    fn extension(&self) -> &'static str {
+        "ods"
+    }
+
+    async fn encode(&self, conn: &Client, selection: &Selection<'_>) -> Response {
+        spreadsheet_rows(conn, selection)
+            .await
+            .and_then(|(head, rows)| {
+                spreadsheet::ods(selection.table, &head, &rows).map_err(internal_error)
+            })
+            .map(|body| ([(CONTENT_TYPE, self.media_type().to_string())], body))
+            .into_response()
+    }
+}
+
+struct XmlEncoder;
+
+impl XmlEncoder {
+    /// Write a row as `<row><column>value</column>...</row>`, with nested json as text.
+    fn row(Row(head, values): Row) -> Result<Vec<u8>, std::convert::Infallible> {
+        let mut xml = String::from("<row>");
+        for (column, value) in head.iter().zip(values) {
+            match value {
+                serde_json::Value::Null => xml.push_str(&format!("<{column} nil=\"true\"/>")),
+                serde_json::Value::String(s) => xml.push_str(&format!(
+                    "<{column}>{}</{column}>",
+                    quick_xml::escape::escape(s.as_str())
+                )),
+                value => xml.push_str(&format!(
+                    "<{column}>{}</{column}>",
+                    quick_xml::escape::escape(value.to_string().as_str())
+                )),
+            }
+        }
+        xml.push_str("</row>");
+        Ok(xml.into_bytes())
+    }
+}
+
+#[async_trait]
+impl Encoder for XmlEncoder {
+    fn media_type(&self) -> &'static MediaType<'static> {
+        &MT_APPLICATION_XML
+    }
+
+    fn extension(&self) -> &'static str {
+        "xml"
+    }
+
+    async fn encode(&self, conn: &Client, selection: &Selection<'_>) -> Response {
+        let table = selection.table;
+        let open = format!(r#"<?xml version="1.0" encoding="UTF-8"?><{table}>"#);
+        let close = format!("</{table}>");
+        encode_rows(
+            conn,
+            selection,
+            self.media_type(),
+            open.into(),
+            close.into(),
+            Self::row,
+        )
+        .await
+    }
+}
+
+struct YamlEncoder;
+
+#[async_trait]
+impl Encoder for YamlEncoder {
+    fn media_type(&self) -> &'static MediaType<'static> {
+        &MT_APPLICATION_YAML
+    }
+
+    fn extension(&self) -> &'static str {
+        "yaml"
+    }
+
+    async fn encode(&self, conn: &Client, selection: &Selection<'_>) -> Response {
+        // every row is a single item sequence, so the body reads as one sequence of maps
+        encode_rows(
+            conn,
+            selection,
+            self.media_type(),
+            Bytes::new(),
+            Bytes::new(),
+            |row| serde_yaml::to_string(&[row]).map(String::into_bytes),
+        )
+        .await
+    }
+}
+
+struct MessagePackEncoder;
+
+#[async_trait]
+impl Encoder for MessagePackEncoder {
+    fn media_type(&self) -> &'static MediaType<'static> {
+        &MT_APPLICATION_MSGPACK
+    }
+
+    fn extension(&self) -> &'static str {
+        "msgpack"
+    }
+
+    async fn encode(&self, conn: &Client, selection: &Selection<'_>) -> Response {
+        // a stream of maps, one per row, like `JsonLines`
+        encode_rows(
+            conn,
+            selection,
+            self.media_type(),
+            Bytes::new(),
+            Bytes::new(),
+            |row| rmp_serde::to_vec(&row),
+        )
+        .await
+    }
+}
+
+struct CborEncoder;
+
+#[async_trait]
+impl Encoder for CborEncoder {
+    fn media_type(&self) -> &'static MediaType<'static> {
+        &MT_APPLICATION_CBOR
+    }
+
+    fn extension(&self) -> &'static str {
+        "cbor"
+    }
+
+    async fn encode(&self, conn: &Client, selection: &Selection<'_>) -> Response {
+        // an indefinite length array keeps the body a single cbor item while streaming
+        encode_rows(
+            conn,
+            selection,
+            self.media_type(),
+            Bytes::from_static(b"\x9f"),
+            Bytes::from_static(b"\xff"),
+            |row| {
+                let mut buf = vec![];
+                ciborium::into_writer(&row, &mut buf).map(|_| buf)
+            },
+        )
+        .await
+    }
+}
+
+struct GeoJsonEncoder;
+
+#[async_trait]
+impl Encoder for GeoJsonEncoder {
+    fn media_type(&self) -> &'static MediaType<'static> {
+        &MT_APPLICATION_GEO_JSON
+    }
+
+    fn extension(&self) -> &'static str {
+        "geojson"
+    }
+
+    /// A `FeatureCollection` of the first selected geometry column, with the other selected
+    /// columns as properties.
+    async fn encode(&self, conn: &Client, selection: &Selection<'_>) -> Response {
+        let head = selection.head();
+        let Some(geometry) = head.iter().find(|c| c.is_geometry()) else {
+            return StatusCode::NOT_ACCEPTABLE.into_response();
+        };
+        let geometry = &geometry.column_name;
+        let properties = head
+            .iter()
+            .filter(|c| !c.is_geometry())
+            .map(|c| format!("'{}', {}", c.column_name, c.masked(&c.column_name)))
+            .join(",");
+        let (table, clause) = (selection.table, selection.clause());
+        let statement = format!(
+            "select json_build_object('type', 'Feature', 'geometry', ST_AsGeoJSON({geometry})::json, 'properties', json_build_object({properties}))::text from {table}{clause};"
+        );
+
+        let features = match conn
+            .query_raw(&statement, std::iter::empty::<&str>())
+            .await
+            .map_err(internal_error)
+        {
+            Ok(rows) => rows,
+            Err(err) => return err.into_response(),
+        };
+        let features = features
+            .map_err(axum::Error::new)
+            .enumerate()
+            .map(|(i, row)| {
+                row.map(|row| {
+                    let feature = row.get::<_, String>(0);
+                    Bytes::from(if i == 0 {
+                        feature
+                    } else {
+                        format!(",{feature}")
+                    })
+                })
+            });
+        let body = stream::once(async {
+            Ok(Bytes::from_static(
+                br#"{"type":"FeatureCollection","features":["#,
+            ))
+        })
+        .chain(features)
+        .chain(stream::once(async { Ok(Bytes::from_static(b"]}")) }));
+
+        (
+            [(CONTENT_TYPE, self.media_type().to_string())],
+            Body::from_stream(body),
+        )
+            .into_response()
+    }
+}
@@ -0,0 +1,444 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use futures::Stream;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio_postgres::{AsyncMessage, Client, Config, NoTls};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+
+const RECONNECT: Duration = Duration::from_secs(1);
+
+/// What happens to a subscriber that falls more than `capacity` notifications behind.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Lagged {
+    /// Skip the notifications it missed.
+    Skip,
+    /// End its subscription, so the client reconnects.
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Limits {
+    /// Notifications buffered per channel.
+    pub(crate) capacity: usize,
+    pub(crate) lagged: Lagged,
+    /// Subscriptions open at once, across all channels.
+    pub(crate) subscribers: usize,
+    /// Longest a subscriber waits for the hub to listen on a new channel.
+    pub(crate) listen: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            capacity: 256,
+            lagged: Lagged::Skip,
+            subscribers: 10_000,
+            listen: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Why [`Hub::subscribe`] failed.
+#[derive(Debug, PartialEq)]
+pub(crate) enum SubscribeError {
+    /// The subscriber cap is reached.
+    Full,
+    /// The hub was not listening in time, the database is likely down.
+    Timeout,
+}
+
+impl fmt::Display for SubscribeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Full => "too many subscribers",
+            Self::Timeout => "not listening yet, try again later",
+        })
+    }
+}
+
+impl std::error::Error for SubscribeError {}
+
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    pub(crate) subscribers: AtomicUsize,
+    pub(crate) rejected: AtomicU64,
+    pub(crate) skipped: AtomicU64,
+    pub(crate) disconnected: AtomicU64,
+    /// SSE streams closed at their maximum lifetime.
+    pub(crate) expired: AtomicU64,
+}
+
+impl Metrics {
+    /// Prometheus text exposition.
+    pub(crate) fn render(&self) -> String {
+        [
+            (
+                "tankard_subscribers",
+                "gauge",
+                "Open subscriptions.",
+                self.subscribers.load(Ordering::Relaxed) as u64,
+            ),
+            (
+                "tankard_subscribers_rejected_total",
+                "counter",
+                "Subscriptions refused at the subscriber cap.",
+                self.rejected.load(Ordering::Relaxed),
+            ),
+            (
+                "tankard_notifications_skipped_total",
+                "counter",
+                "Notifications lagging subscribers missed.",
+                self.skipped.load(Ordering::Relaxed),
+            ),
+            (
+                "tankard_subscribers_disconnected_total",
+                "counter",
+                "Subscriptions ended for lagging.",
+                self.disconnected.load(Ordering::Relaxed),
+            ),
+            (
+                "tankard_sse_expired_total",
+                "counter",
+                "SSE streams closed at their maximum lifetime.",
+                self.expired.load(Ordering::Relaxed),
+            ),
+        ]
+        .iter()
+        .map(|(name, kind, help, value)| {
+            format!("# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n")
+        })
+        .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Notification {
+    pub(crate) payload: String,
+}
+
+#[derive(Debug)]
+enum Command {
+    Listen(String, oneshot::Sender<()>),
+    Unlisten(String),
+}
+
+#[derive(Debug)]
+struct Channel {
+    sender: broadcast::Sender<Notification>,
+    subscribers: usize,
+}
+
+#[derive(Debug)]
+struct Inner {
+    channels: Mutex<HashMap<String, Channel>>,
+    commands: mpsc::UnboundedSender<Command>,
+    limits: Limits,
+    metrics: Metrics,
+}
+
+impl Inner {
+    fn unsubscribe(&self, channel: &str) {
+        self.metrics.subscribers.fetch_sub(1, Ordering::Relaxed);
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(c) = channels.get_mut(channel) {
+            c.subscribers -= 1;
+            if c.subscribers == 0 {
+                channels.remove(channel);
+                _ = self.commands.send(Command::Unlisten(channel.to_string()));
+            }
+        }
+    }
+}
+
+/// Fans notifications out to subscribers from a single `LISTEN` connection.
+#[derive(Debug, Clone)]
+pub(crate) struct Hub(Arc<Inner>);
+
+impl Hub {
+    /// Spawn the listener task, which (re)connects with `config` for as long as the hub lives.
+    pub(crate) fn new(config: Config, limits: Limits) -> Self {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let inner = Arc::new(Inner {
+            channels: Mutex::default(),
+            commands,
+            limits,
+            metrics: Metrics::default(),
+        });
+        tokio::spawn(run(config, Arc::downgrade(&inner), receiver));
+        Self(inner)
+    }
+
+    pub(crate) fn metrics(&self) -> &Metrics {
+        &self.0.metrics
+    }
+
+    /// Subscribe to `channel`, returning once the hub is listening on it.
+    pub(crate) async fn subscribe(&self, channel: &str) -> Result<Subscription, SubscribeError> {
+        let metrics = &self.0.metrics;
+        if metrics.subscribers.fetch_add(1, Ordering::Relaxed) >= self.0.limits.subscribers {
+            metrics.subscribers.fetch_sub(1, Ordering::Relaxed);
+            metrics.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(SubscribeError::Full);
+        }
+
+        let (receiver, listening) = {
+            let mut channels = self.0.channels.lock().unwrap();
+            match channels.get_mut(channel) {
+                Some(c) => {
+                    c.subscribers += 1;
+                    (c.sender.subscribe(), None)
+                }
+                None => {
+                    let (sender, receiver) = broadcast::channel(self.0.limits.capacity);
+                    channels.insert(
+                        channel.to_string(),
+                        Channel {
+                            sender,
+                            subscribers: 1,
+                        },
+                    );
+                    let (ack, listening) = oneshot::channel();
+                    _ = self
+                        .0
+                        .commands
+                        .send(Command::Listen(channel.to_string(), ack));
+                    (receiver, Some(listening))
+                }
+            }
+        };
+        let subscription = Subscription {
+            channel: channel.to_string(),
+            stream: BroadcastStream::new(receiver),
+            inner: self.0.clone(),
+        };
+        // dropping the subscription takes it back
+        if let Some(listening) = listening {
+            if tokio::time::timeout(self.0.limits.listen, listening)
+                .await
+                .is_err()
+            {
+                return Err(SubscribeError::Timeout);
+            }
+        }
+        Ok(subscription)
+    }
+
+    /// Send a notification to the subscribers of this hub only.
+    pub(crate) fn publish(&self, channel: &str, payload: &str) {
+        if let Some(c) = self.0.channels.lock().unwrap().get(channel) {
+            _ = c.sender.send(Notification {
+                payload: payload.to_string(),
+            });
+        }
+    }
+}
+
+/// Notifications of one channel, unsubscribing when dropped.
+pub(crate) struct Subscription {
+    channel: String,
+    stream: BroadcastStream<Notification>,
+    inner: Arc<Inner>,
+}
+
+impl Stream for Subscription {
+    type Item = Notification;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match ready!(Pin::new(&mut self.stream).poll_next(cx)) {
+                Some(Ok(notification)) => return Poll::Ready(Some(notification)),
+                Some(Err(BroadcastStreamRecvError::Lagged(skipped))) => {
+                    let metrics = &self.inner.metrics;
+                    metrics.skipped.fetch_add(skipped, Ordering::Relaxed);
+                    if self.inner.limits.lagged == Lagged::Disconnect {
+                        metrics.disconnected.fetch_add(1, Ordering::Relaxed);
+                        return Poll::Ready(None);
+                    }
+                }
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.inner.unsubscribe(&self.channel);
+    }
+}
+
+/// Quote a channel name as an identifier.
+pub(crate) fn ident(channel: &str) -> String {
+    format!("\"{}\"", channel.replace('"', "\"\""))
+}
+
+async fn run(
+    config: Config,
+    inner: std::sync::Weak<Inner>,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+) {
+    loop {
+        let (client, mut connection) = match config.connect(NoTls).await {
+            Ok(conn) => conn,
+            Err(_) => {
+                tokio::time::sleep(RECONNECT).await;
+                continue;
+            }
+        };
+
+        // the connection is driven on its own task, so queries can run while messages arrive
+        let (sender, mut messages) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(Ok(message)) =
+                std::future::poll_fn(|cx| connection.poll_message(cx)).await
+            {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let Some(hub) = inner.upgrade() else {
+            return;
+        };
+        let channels = hub.channels.lock().unwrap().keys().cloned().collect();
+        drop(hub);
+        if listen(&client, channels).await.is_err() {
+            tokio::time::sleep(RECONNECT).await;
+            continue;
+        }
+
+        loop {
+            tokio::select! {
+                command = commands.recv() => match command {
+                    Some(Command::Listen(channel, ack)) => {
+                        if listen(&client, vec![channel]).await.is_err() {
+                            break;
+                        }
+                        _ = ack.send(());
+                    }
+                    Some(Command::Unlisten(channel)) => {
+                        if client
+                            .batch_execute(&format!("unlisten {};", ident(&channel)))
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    None => return,
+                },
+                message = messages.recv() => match message {
+                    Some(AsyncMessage::Notification(n)) => {
+                        if let Some(hub) = inner.upgrade() {
+                            Hub(hub).publish(n.channel(), n.payload());
+                        }
+                    }
+                    Some(_) => {}
+                    None => break,
+                },
+            }
+        }
+
+        tokio::time::sleep(RECONNECT).await;
+    }
+}
+
+async fn listen(client: &Client, channels: Vec<String>) -> Result<(), tokio_postgres::Error> {
+    if channels.is_empty() {
+        return Ok(());
+    }
+    client
+        .batch_execute(
+            &channels
+                .iter()
+                .map(|channel| format!("listen {};", ident(channel)))
+                .collect::<String>(),
+        )
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, str::FromStr, sync::atomic::Ordering, time::Duration};
+
+    use super::{Hub, Lagged, Limits, SubscribeError};
+
+    #[tokio::test]
+    async fn subscriber_cap() -> Result<(), Box<dyn Error>> {
+        let config = tokio_postgres::Config::from_str("postgres://localhost:28817/postgres")?;
+        let hub = Hub::new(
+            config,
+            Limits {
+                subscribers: 1,
+                ..Limits::default()
+            },
+        );
+
+        let subscription = hub.subscribe("foo").await;
+        assert!(subscription.is_ok());
+        assert_eq!(hub.subscribe("bar").await.err(), Some(SubscribeError::Full));
+        assert_eq!(hub.metrics().rejected.load(Ordering::Relaxed), 1);
+
+        drop(subscription);
+        assert!(hub.subscribe("bar").await.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn listen_timeout() -> Result<(), Box<dyn Error>> {
+        // nothing listens on the port, so the hub never connects
+        let config = tokio_postgres::Config::from_str("postgres://localhost:1/postgres")?;
+        let hub = Hub::new(
+            config,
+            Limits {
+                listen: Duration::from_millis(100),
+                ..Limits::default()
+            },
+        );
+
+        assert_eq!(
+            hub.subscribe("foo").await.err(),
+            Some(SubscribeError::Timeout)
+        );
+        assert_eq!(hub.metrics().subscribers.load(Ordering::Relaxed), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lagged_disconnect() -> Result<(), Box<dyn Error>> {
+        use futures::StreamExt;
+
+        let config = tokio_postgres::Config::from_str("postgres://localhost:28817/postgres")?;
+        let hub = Hub::new(
+            config,
+            Limits {
+                capacity: 1,
+                lagged: Lagged::Disconnect,
+                ..Limits::default()
+            },
+        );
+
+        let mut subscription = hub.subscribe("foo").await.unwrap();
+        hub.publish("foo", "one");
+        hub.publish("foo", "two");
+
+        assert!(subscription.next().await.is_none());
+        assert_eq!(hub.metrics().skipped.load(Ordering::Relaxed), 1);
+        assert_eq!(hub.metrics().disconnected.load(Ordering::Relaxed), 1);
+
+        Ok(())
+    }
+}
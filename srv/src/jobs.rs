@@ -0,0 +1,357 @@
+use std::{collections::HashMap, convert::Infallible, future::Future, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Query, State},
+    http::{header::LINK, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use axum_extra::{extract::JsonLines, TypedHeader};
+use bb8_postgres::PostgresConnectionManager;
+use futures::{future::BoxFuture, stream, StreamExt};
+use headers_accept::Accept;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::time::Instant;
+use tokio_postgres::{error::SqlState, NoTls};
+
+use crate::{
+    api::{Context, Transaction},
+    audit::{next, PAGE, PAGE_MAX},
+    encoder::MT_APPLICATION_JSON,
+    hub::{Hub, SubscribeError},
+    internal_error, AppState,
+};
+
+/// Channel `tankard_enqueue` notifies on.
+const CHANNEL: &str = "tankard_jobs";
+/// Check for due jobs this often, for those scheduled with `run_at`.
+const POLL: Duration = Duration::from_secs(5);
+/// Keep running jobs claimed this often, well within the timeout of `tankard_dequeue`.
+const HEARTBEAT: Duration = Duration::from_secs(60);
+
+/// A claimed job, as handlers get it.
+#[derive(Debug)]
+pub(crate) struct Job {
+    pub(crate) payload: Value,
+    /// Attempts so far, this one included.
+    pub(crate) attempts: i32,
+    pub(crate) max_attempts: i32,
+}
+
+impl Job {
+    /// Whether the job fails for good if this attempt does.
+    pub(crate) fn last(&self) -> bool {
+        self.attempts >= self.max_attempts
+    }
+}
+
+/// Runs a job, an error fails the attempt.
+pub(crate) type Handler = Box<dyn Fn(Job) -> BoxFuture<'static, Result<(), String>> + Send + Sync>;
+
+/// Job handlers by kind.
+#[derive(Default)]
+pub(crate) struct Handlers(HashMap<String, Handler>);
+
+impl Handlers {
+    pub(crate) fn register<F, Fut>(mut self, kind: &str, handler: F) -> Self
+    where
+        F: Fn(Job) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.0.insert(
+            kind.to_string(),
+            Box::new(move |job| Box::pin(handler(job))),
+        );
+        self
+    }
+}
+
+/// Claim and run the jobs of `queue` one at a time, for the kinds there are handlers of.
+pub(crate) async fn work(
+    pool: &'static bb8::Pool<PostgresConnectionManager<NoTls>>,
+    hub: Hub,
+    handlers: Arc<Handlers>,
+    queue: String,
+) {
+    let mut enqueued = loop {
+        match hub.subscribe(CHANNEL).await {
+            Ok(enqueued) => break enqueued,
+            // the database is not up yet
+            Err(SubscribeError::Timeout) => {}
+            Err(SubscribeError::Full) => return,
+        }
+    };
+    let kinds = handlers.0.keys().cloned().collect::<Vec<_>>();
+
+    loop {
+        while let Some((id, kind, job)) = dequeue(pool, &queue, &kinds).await {
+            let Some(handler) = handlers.0.get(&kind) else {
+                continue;
+            };
+            let mut handle = tokio::spawn(handler(job));
+            let mut heartbeat = tokio::time::interval_at(Instant::now() + HEARTBEAT, HEARTBEAT);
+            let result = loop {
+                tokio::select! {
+                    // a panicking handler fails its job rather than the worker
+                    result = &mut handle => break match result {
+                        Ok(result) => result,
+                        Err(err) => Err(err.to_string()),
+                    },
+                    _ = heartbeat.tick() => {
+                        if let Ok(conn) = pool.get().await {
+                            _ = conn.execute("select tankard_heartbeat($1);", &[&id]).await;
+                        }
+                    }
+                }
+            };
+            let Ok(conn) = pool.get().await else {
+                break;
+            };
+            _ = match result {
+                Ok(()) => conn.execute("select tankard_complete($1);", &[&id]).await,
+                Err(err) => {
+                    conn.execute("select tankard_fail($1, $2);", &[&id, &err])
+                        .await
+                }
+            };
+        }
+
+        tokio::select! {
+            _ = enqueued.next() => {}
+            _ = tokio::time::sleep(POLL) => {}
+        }
+    }
+}
+
+/// Query parameters of `/api/_jobs`.
+#[derive(Debug, Deserialize)]
+pub(crate) struct JobsQuery {
+    queue: Option<String>,
+    kind: Option<String>,
+    status: Option<String>,
+    /// Jobs after this id, the `Link` of the previous page.
+    after: Option<i64>,
+    limit: Option<i64>,
+}
+
+/// Jobs of `tankard_jobs` as JSON lines, read as the role of the request, so only to roles
+/// granted `select` on it. Pages are `limit` jobs by `id`, a `Link` header points to the next.
+pub(crate) async fn jobs(
+    uri: Uri,
+    Query(query): Query<JobsQuery>,
+    TypedHeader(accept): TypedHeader<Accept>,
+    State(AppState { pool, .. }): State<AppState>,
+    context: Context,
+) -> Result<Response, (StatusCode, String)> {
+    if accept.negotiate([&MT_APPLICATION_JSON]).is_none() {
+        return Ok(StatusCode::NOT_ACCEPTABLE.into_response());
+    }
+    if let Some(key) = &context.api_key {
+        key.authorize("read", "_jobs")?;
+    }
+    let limit = query.limit.unwrap_or(PAGE).clamp(1, PAGE_MAX);
+
+    let conn = Transaction::begin(pool, &context).await?;
+    let jobs = conn
+        .query(
+            "select to_jsonb(j) from tankard_jobs j
+             where ($1::text is null or queue = $1)
+             and ($2::text is null or kind = $2)
+             and ($3::text is null or status = $3)
+             and ($4::bigint is null or id > $4)
+             order by id
+             limit $5;",
+            &[
+                &query.queue,
+                &query.kind,
+                &query.status,
+                &query.after,
+                &limit,
+            ],
+        )
+        .await
+        .map_err(|err| match err.code() {
+            Some(&SqlState::INSUFFICIENT_PRIVILEGE) => (StatusCode::FORBIDDEN, err.to_string()),
+            _ => internal_error(err),
+        })?
+        .into_iter()
+        .map(|row| row.get::<_, Value>(0))
+        .collect::<Vec<_>>();
+    let link = jobs
+        .last()
+        .filter(|_| jobs.len() as i64 == limit)
+        .and_then(|job| job["id"].as_i64())
+        .map(|id| [(LINK, format!("<{}>; rel=\"next\"", next(&uri, id)))]);
+    let jobs = jobs.into_iter().map(Ok::<_, Infallible>);
+    Ok((link, JsonLines::new(stream::iter(jobs))).into_response())
+}
+
+async fn dequeue(
+    pool: &'static bb8::Pool<PostgresConnectionManager<NoTls>>,
+    queue: &str,
+    kinds: &[String],
+) -> Option<(i64, String, Job)> {
+    let conn = pool.get().await.ok()?;
+    conn.query_opt(
+        "select id, kind, payload, attempts, max_attempts from tankard_dequeue($1, $2);",
+        &[&queue, &kinds],
+    )
+    .await
+    .ok()
+    .flatten()
+    .map(|row| {
+        let job = Job {
+            payload: row.get(2),
+            attempts: row.get(3),
+            max_attempts: row.get(4),
+        };
+        (row.get(0), row.get(1), job)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, sync::Arc, time::Duration};
+
+    use axum::{
+        body::Body,
+        extract::Request,
+        http::{
+            header::{ACCEPT, AUTHORIZATION, LINK},
+            StatusCode,
+        },
+    };
+    use http_body_util::BodyExt;
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc;
+    use tower::ServiceExt;
+
+    use super::{work, Handlers, Job};
+    use crate::{
+        hub::{Hub, Limits},
+        jwt::tests::token,
+        tests::{setup_app, setup_pool},
+    };
+
+    #[tokio::test]
+    async fn jobs_work() -> Result<(), Box<dyn Error>> {
+        let db_name = "9a4e1c7b-3f2d-4b86-a5e0-6c8d2f1b7e93";
+        let (conn, _) = setup_app(db_name).await?;
+        let (pool, config) = setup_pool(db_name).await?;
+
+        let (sender, mut payloads) = mpsc::unbounded_channel();
+        let handlers = Handlers::default()
+            .register("echo", move |job: Job| {
+                let sender = sender.clone();
+                async move { sender.send(job.payload).map_err(|e| e.to_string()) }
+            })
+            .register("boom", |_| async { Err("boom".to_string()) });
+        tokio::spawn(work(
+            pool,
+            Hub::new(config, Limits::default()),
+            Arc::new(handlers),
+            "default".to_string(),
+        ));
+
+        conn.batch_execute(
+            r#"select tankard_enqueue('boom', max_attempts => 1); select tankard_enqueue('echo', '{"to": "foo"}');"#,
+        )
+        .await?;
+
+        assert_eq!(payloads.recv().await, Some(json!({ "to": "foo" })));
+
+        let mut statuses = vec![];
+        for _ in 0..50 {
+            statuses = conn
+                .query(
+                    "select kind, status, error from tankard_jobs order by id;",
+                    &[],
+                )
+                .await?
+                .iter()
+                .map(|row| {
+                    (
+                        row.get::<_, String>(0),
+                        row.get::<_, String>(1),
+                        row.get::<_, Option<String>>(2),
+                    )
+                })
+                .collect::<Vec<_>>();
+            if statuses.iter().all(|(_, status, _)| status != "running") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(
+            statuses,
+            vec![
+                (
+                    "boom".to_string(),
+                    "failed".to_string(),
+                    Some("boom".to_string())
+                ),
+                ("echo".to_string(), "done".to_string(), None),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn jobs_api() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("2d7b4f9e-1a6c-4e03-8f5b-c9e2a7d1b460").await?;
+        conn.batch_execute(
+            "select tankard_enqueue('echo'); select tankard_enqueue('boom'); select tankard_enqueue('echo'); do $$ begin create role tankard_reader; exception when duplicate_object or unique_violation then null; end $$;",
+        )
+        .await?;
+
+        let get = |uri: &str, authorization: Option<String>| {
+            let mut request = Request::builder()
+                .uri(uri)
+                .header(ACCEPT, "application/json");
+            if let Some(authorization) = authorization {
+                request = request.header(AUTHORIZATION, authorization);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let response = get("/api/_jobs?kind=echo&limit=1", None).await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let link = response.headers()[LINK].to_str()?.to_string();
+        let body = response.into_body().collect().await?.to_bytes();
+        let job = serde_json::from_slice::<Value>(&body)?;
+        assert_eq!(job["kind"], "echo");
+        assert_eq!(job["status"], "pending");
+
+        let next = link
+            .strip_prefix('<')
+            .and_then(|link| link.split_once('>'))
+            .map(|(next, _)| next.to_string())
+            .unwrap();
+        let body = get(&next, None)
+            .await?
+            .into_body()
+            .collect()
+            .await?
+            .to_bytes();
+        let job = serde_json::from_slice::<Value>(&body)?;
+        assert_eq!(job["kind"], "echo");
+        assert!(job["id"].as_i64() > Some(1));
+
+        // `tankard_jobs` is granted to no role but the server's
+        let response = get(
+            "/api/_jobs",
+            Some(format!(
+                "Bearer {}",
+                token(json!({ "role": "tankard_reader" }))
+            )),
+        )
+        .await?;
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,216 @@
+use std::{error::Error, fmt};
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header::WWW_AUTHENTICATE, request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{AlgorithmParameters, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use serde_json::{Map, Value};
+
+use crate::AppState;
+
+struct Key {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+/// Keys bearer tokens are validated with.
+#[derive(Default)]
+pub(crate) struct Keys(Vec<Key>);
+
+impl Keys {
+    /// Keys of the files named by `TANKARD_JWT_SECRET` (HS256), `TANKARD_JWT_PUBLIC_KEY` (PEM,
+    /// RS256) and `TANKARD_JWKS`.
+    pub(crate) fn load() -> Result<Self, Box<dyn Error>> {
+        let mut keys = Self::default();
+        if let Ok(path) = std::env::var("TANKARD_JWT_SECRET") {
+            keys = keys.secret(std::fs::read(path)?.trim_ascii());
+        }
+        if let Ok(path) = std::env::var("TANKARD_JWT_PUBLIC_KEY") {
+            keys = keys.rsa_pem(&std::fs::read(path)?)?;
+        }
+        if let Ok(path) = std::env::var("TANKARD_JWKS") {
+            keys = keys.jwks(&std::fs::read_to_string(path)?)?;
+        }
+        Ok(keys)
+    }
+
+    pub(crate) fn secret(mut self, secret: &[u8]) -> Self {
+        self.0.push(Key {
+            kid: None,
+            algorithm: Algorithm::HS256,
+            key: DecodingKey::from_secret(secret),
+        });
+        self
+    }
+
+    pub(crate) fn rsa_pem(mut self, pem: &[u8]) -> Result<Self, jsonwebtoken::errors::Error> {
+        self.0.push(Key {
+            kid: None,
+            algorithm: Algorithm::RS256,
+            key: DecodingKey::from_rsa_pem(pem)?,
+        });
+        Ok(self)
+    }
+
+    /// The HS256 and RS256 keys of a JWKS document, others are skipped.
+    pub(crate) fn jwks(mut self, json: &str) -> Result<Self, Box<dyn Error>> {
+        for jwk in serde_json::from_str::<JwkSet>(json)?.keys {
+            let algorithm = match (&jwk.algorithm, jwk.common.key_algorithm) {
+                (_, Some(algorithm)) => algorithm.to_string().parse()?,
+                (AlgorithmParameters::RSA(_), None) => Algorithm::RS256,
+                (AlgorithmParameters::OctetKey(_), None) => Algorithm::HS256,
+                _ => continue,
+            };
+            if !matches!(algorithm, Algorithm::HS256 | Algorithm::RS256) {
+                continue;
+            }
+            self.0.push(Key {
+                kid: jwk.common.key_id.clone(),
+                algorithm,
+                key: DecodingKey::from_jwk(&jwk)?,
+            });
+        }
+        Ok(self)
+    }
+
+    /// Claims of a token signed by one of the keys, with `exp` and `nbf` checked.
+    pub(crate) fn decode(&self, token: &str) -> Option<Map<String, Value>> {
+        let header = decode_header(token).ok()?;
+        self.0
+            .iter()
+            .filter(|key| key.algorithm == header.alg)
+            .filter(|key| key.kid.is_none() || header.kid.is_none() || key.kid == header.kid)
+            .find_map(|key| {
+                let mut validation = Validation::new(key.algorithm);
+                validation.validate_aud = false;
+                validation.validate_nbf = true;
+                decode(token, &key.key, &validation)
+                    .ok()
+                    .map(|data| data.claims)
+            })
+    }
+}
+
+impl fmt::Debug for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|key| (key.algorithm, &key.kid)))
+            .finish()
+    }
+}
+
+/// Claims of the bearer token of a request, `None` without one.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Claims(pub(crate) Option<Map<String, Value>>);
+
+impl Claims {
+    /// The `role` claim, which queries of the request run as.
+    pub(crate) fn role(&self) -> Option<&str> {
+        self.0.as_ref()?.get("role")?.as_str()
+    }
+
+    /// The `exp` claim, in seconds since the epoch.
+    pub(crate) fn expires(&self) -> Option<i64> {
+        self.0.as_ref()?.get("exp")?.as_i64()
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Claims {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Ok(TypedHeader(Authorization(bearer))) =
+            TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state).await
+        else {
+            return Ok(Self(None));
+        };
+        state
+            .jwt
+            .decode(bearer.token())
+            .map(|claims| Self(Some(claims)))
+            .ok_or_else(|| {
+                (
+                    StatusCode::UNAUTHORIZED,
+                    [(WWW_AUTHENTICATE, r#"Bearer error="invalid_token""#)],
+                )
+                    .into_response()
+            })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    use super::Keys;
+
+    pub(crate) const SECRET: &[u8] = b"secret";
+
+    /// HS256 token of `claims` signed with [`SECRET`], expiring in an hour unless they set `exp`.
+    pub(crate) fn token(mut claims: Value) -> String {
+        if claims.get("exp").is_none() {
+            claims["exp"] = (chrono::Utc::now().timestamp() + 3600).into();
+        }
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn decode_secret() {
+        let keys = Keys::default().secret(SECRET);
+
+        assert_eq!(
+            keys.decode(&token(json!({ "role": "foo" })))
+                .and_then(|claims| claims.get("role").cloned()),
+            Some(json!("foo"))
+        );
+        assert_eq!(
+            Keys::default()
+                .secret(b"other")
+                .decode(&token(json!({ "role": "foo" }))),
+            None
+        );
+
+        let expired = encode(
+            &Header::default(),
+            &json!({ "role": "foo", "exp": 0 }),
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap();
+        assert_eq!(keys.decode(&expired), None);
+    }
+
+    #[test]
+    fn decode_jwks() -> Result<(), Box<dyn std::error::Error>> {
+        // "secret", base64url encoded
+        let keys = Keys::default().jwks(
+            &json!({ "keys": [{ "kty": "oct", "kid": "one", "alg": "HS256", "k": "c2VjcmV0" }] })
+                .to_string(),
+        )?;
+
+        assert!(keys.decode(&token(json!({}))).is_some());
+
+        Ok(())
+    }
+}
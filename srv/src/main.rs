@@ -1,20 +1,65 @@
-use std::{collections::HashMap, error::Error};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    error::Error,
+    future,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
 
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
-    response::{sse::Event, Html, Sse},
+    middleware,
+    response::{
+        sse::{Event, KeepAlive},
+        Html, Sse,
+    },
     routing::get,
     Extension, Router,
 };
 use bb8_postgres::PostgresConnectionManager;
-use futures::{channel::mpsc, FutureExt, Stream, StreamExt, TryFutureExt, TryStreamExt};
+use encoder::Encoders;
+use futures::{stream, Stream, StreamExt};
+use hub::{Hub, Limits, SubscribeError};
+use itertools::Itertools;
+use jobs::Handlers;
+use serde::Deserialize;
+use serde_json::json;
 use tokio::net::TcpListener;
-use tokio_postgres::{AsyncMessage, NoTls};
+use tokio_postgres::NoTls;
 use tower_http::services::ServeDir;
 
 mod api;
+mod api_keys;
+mod audit;
+mod auth;
+mod changes;
+mod csrf;
+mod encoder;
+mod hub;
+mod jobs;
+mod jwt;
 mod parser;
+mod privileges;
+mod rate_limits;
+mod replication;
+mod spreadsheet;
+mod webhooks;
+mod ws;
+
+/// Longest an SSE stream stays open.
+const SSE_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// Background job workers.
+const WORKERS: usize = 4;
+const COALESCE_WINDOW: Duration = Duration::from_millis(250);
+const COALESCE_WINDOW_MAX: Duration = Duration::from_secs(10);
+/// Notifications coalesced into one event at most.
+const COALESCE_MAX: usize = 10_000;
+/// Primary keys sent with a coalesced event at most.
+const COALESCE_PKS: usize = 100;
 
 fn internal_error<E>(err: E) -> (StatusCode, String)
 where
@@ -25,60 +70,154 @@ where
 
 async fn index(
     State(AppState { pool, .. }): State<AppState>,
+    Extension(csrf::CsrfToken(csrf)): Extension<csrf::CsrfToken>,
 ) -> Result<Html<String>, (StatusCode, String)> {
     let conn = match pool.get().await.map_err(internal_error) {
         Ok(conn) => conn,
         Err(err) => return Err(err),
     };
-    conn.query_one("select html_minify(html_index());", &[])
+    conn.query_one("select html_minify(html_index($1));", &[&csrf])
         .await
         .map(|row| Html(row.get(0)))
         .map_err(internal_error)
 }
 
+#[derive(Debug, Deserialize)]
+struct Coalesce {
+    /// Milliseconds notifications of a channel are collected into one event.
+    window: Option<u64>,
+}
+
+/// Payload of a notification sent once per statement, rather than with the primary key of a row.
+#[derive(Debug, Deserialize)]
+struct Statement {
+    /// Rows the statement changed.
+    count: u64,
+    /// Primary keys of those rows, `None` when there were too many.
+    pks: Option<Vec<String>>,
+}
+
+/// Stream notifications of a channel registered with `tankard_allow_listen`.
+///
+/// Notifications within the coalescing window become one event with the number of rows changed
+/// and their primary keys, which row level triggers send as payloads and statement level ones as
+/// a [`Statement`]. Whether the channel may be listened to is up to the role of the request.
 async fn listen(
     Path(event): Path<String>,
-) -> Result<Sse<impl Stream<Item = Result<Event, String>>>, (StatusCode, String)> {
-    let db_url = std::option_env!("DATABASE_URL").unwrap_or("postgres://localhost:28816/tankard");
-    let (client, mut conn) = match tokio_postgres::connect(db_url, NoTls)
-        .map_err(internal_error)
+    Query(Coalesce { window }): Query<Coalesce>,
+    State(AppState { hub, .. }): State<AppState>,
+    conn: api::Transaction,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    let allowed = conn
+        .query_one("select tankard_can_listen($1);", &[&event])
         .await
-    {
-        Ok(conn) => conn,
-        Err(err) => return Err(err),
-    };
+        .map_err(internal_error)?
+        .get::<_, Option<bool>>(0);
+    match allowed {
+        Some(true) => {}
+        Some(false) => return Err((StatusCode::FORBIDDEN, event)),
+        None => return Err((StatusCode::NOT_FOUND, event)),
+    }
+    drop(conn);
 
-    let (tx, rx) = mpsc::unbounded();
+    let notifications = hub.subscribe(&event).await.map_err(unavailable)?;
+    let window = window.map_or(COALESCE_WINDOW, Duration::from_millis);
 
-    let stream =
-        futures::stream::poll_fn(move |cx| conn.poll_message(cx)).map_err(|e| panic!("{}", e));
-    let connection = stream.forward(tx).map(|r| r.unwrap());
-    tokio::spawn(connection);
+    Ok(sse(
+        hub,
+        tokio_stream::StreamExt::chunks_timeout(
+            notifications,
+            COALESCE_MAX,
+            window.min(COALESCE_WINDOW_MAX),
+        )
+        .map(move |notifications| {
+            let mut count = 0;
+            let mut pks = Some(Vec::new());
+            for notification in notifications {
+                match serde_json::from_str::<Statement>(&notification.payload) {
+                    Ok(statement) => {
+                        count += statement.count;
+                        match (&mut pks, statement.pks) {
+                            (Some(pks), Some(rows)) => pks.extend(rows),
+                            _ => pks = None,
+                        }
+                    }
+                    Err(_) => {
+                        count += 1;
+                        if let (Some(pks), false) = (&mut pks, notification.payload.is_empty()) {
+                            pks.push(notification.payload);
+                        }
+                    }
+                }
+            }
+            // clients refresh everything rather than this many rows
+            let pks = pks
+                .map(|pks| pks.into_iter().unique().collect::<Vec<_>>())
+                .filter(|pks| pks.len() <= COALESCE_PKS);
+            Event::default()
+                .event(&event)
+                .data(json!({ "count": count, "pks": pks }).to_string())
+        }),
+    ))
+}
 
-    // FIXME: remove endless loop and keep client alive properly
-    tokio::spawn(async move {
-        _ = client
-            .batch_execute(&format!("listen {event};notify {event};"))
-            .await;
-        loop {}
-    });
+fn unavailable(err: SubscribeError) -> (StatusCode, String) {
+    (StatusCode::SERVICE_UNAVAILABLE, err.to_string())
+}
 
-    let notifications = rx.map(|m| match m {
-        AsyncMessage::Notification(n) => Ok(Event::default().event(n.channel()).data(n.payload())),
-        AsyncMessage::Notice(_) => Ok(Event::default()),
-        _ => Ok(Event::default()),
+/// SSE response with keep-alive comments, closed after [`SSE_LIFETIME`] so clients reconnect.
+fn sse(
+    hub: Hub,
+    events: impl Stream<Item = Event> + Send + 'static,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let expired = stream::once(async move {
+        tokio::time::sleep(SSE_LIFETIME).await;
+        hub.metrics().expired.fetch_add(1, Ordering::Relaxed);
+        None
     });
+    Sse::new(
+        stream::select(
+            events.map(Some).chain(stream::once(async { None })),
+            expired,
+        )
+        .take_while(|event| future::ready(event.is_some()))
+        .filter_map(future::ready)
+        .map(Ok),
+    )
+    .keep_alive(KeepAlive::default())
+}
+
+async fn metrics(State(hub): State<Hub>) -> String {
+    hub.metrics().render()
+}
 
-    Ok(Sse::new(notifications))
+/// `/metrics`, served apart from the app on an address of its own, as it is not authenticated.
+fn metrics_router(hub: Hub) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .with_state(hub)
 }
 
 #[derive(Debug, Clone)]
 struct AppState {
     pool: &'static bb8::Pool<PostgresConnectionManager<NoTls>>,
+    encoders: &'static Encoders,
+    hub: Hub,
+    /// Hub channel carrying row changes, see `changes` and `replication`.
+    changes: &'static str,
+    jwt: &'static jwt::Keys,
+    privileges: &'static privileges::Privileges,
+    rate_limits: &'static rate_limits::RateLimits,
+    contexts: &'static api::ContextConfig,
 }
 
 async fn app(
     pool: &'static bb8::Pool<PostgresConnectionManager<NoTls>>,
+    hub: Hub,
+    changes: &'static str,
+    jwt: jwt::Keys,
+    rate_limits: rate_limits::RateLimits,
+    contexts: api::ContextConfig,
 ) -> Result<Router, Box<dyn Error>> {
     // TODO: live refresh of schema
     let conn = pool.get().await?;
@@ -97,41 +236,142 @@ async fn app(
                 .collect::<HashMap<_, _>>()
         })?;
 
+    let state = AppState {
+        pool,
+        encoders: Box::leak(Box::new(Encoders::default())),
+        hub,
+        changes,
+        jwt: Box::leak(Box::new(jwt)),
+        privileges: Box::leak(Box::default()),
+        rate_limits: Box::leak(Box::new(rate_limits)),
+        contexts: Box::leak(Box::new(contexts)),
+    };
+    if let Ok(changes) = state.hub.subscribe(changes).await {
+        tokio::spawn(webhooks::run(changes, tables.clone(), state.clone()));
+    }
+
     Ok(Router::new()
         .route("/", get(index))
+        .nest("/auth", auth::router())
+        .route("/api/_audit", get(audit::audit))
+        .route("/api/_jobs", get(jobs::jobs))
         .nest("/api/:table", api::router())
         .route("/listen/:event", get(listen))
+        .route("/ws", get(ws::ws))
         .fallback_service(ServeDir::new("dist"))
+        // after the session and api key, so requests count against them
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limits::limit,
+        ))
+        .layer(middleware::from_fn_with_state(state.clone(), auth::session))
+        .layer(middleware::from_fn(csrf::protect))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            api_keys::api_key,
+        ))
         .layer(Extension(tables))
-        .with_state(AppState { pool }))
+        .with_state(state))
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let db_url = std::option_env!("DATABASE_URL").unwrap_or("postgres://localhost:28816/tankard");
+    let config = tokio_postgres::Config::from_str(db_url)?;
 
-    let manager = PostgresConnectionManager::new_from_stringlike(db_url, NoTls)?;
+    let manager = PostgresConnectionManager::new(config.clone(), NoTls);
     let pool = bb8::Pool::builder().build(manager).await?;
 
-    let app = app(Box::leak(Box::new(pool))).await?;
+    let pool = &*Box::leak(Box::new(pool));
+
+    // `tankard_srv api-key ...` manages api keys instead of serving
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if let Some(("api-key", args)) = args.split_first().map(|(c, a)| (c.as_str(), a)) {
+        return api_keys::cli(pool, args).await;
+    }
+
+    tokio::spawn(changes::prune(pool));
+
+    // `TANKARD_CAPTURE=replication` reads changes from a logical replication slot instead of triggers
+    let replication =
+        std::env::var("TANKARD_CAPTURE").is_ok_and(|capture| capture == "replication");
+    let hub = Hub::new(config, Limits::default());
+    let changes = if replication {
+        let slot = replication::slot();
+        let conn = replication::start(pool, &slot).await?;
+        tokio::spawn(replication::run(pool, hub.clone(), slot, conn));
+        replication::CHANNEL
+    } else {
+        changes::CHANNEL
+    };
+
+    // handlers of background jobs, like `.register("email", send_email)`
+    let client = webhooks::client();
+    let handlers = Arc::new(Handlers::default().register(webhooks::JOB, move |job| {
+        webhooks::deliver(client.clone(), pool, job)
+    }));
+    for _ in 0..WORKERS {
+        tokio::spawn(jobs::work(
+            pool,
+            hub.clone(),
+            handlers.clone(),
+            "default".to_string(),
+        ));
+    }
+
+    let rate_limits = rate_limits::RateLimits::load()?;
+    if rate_limits.postgres {
+        tokio::spawn(rate_limits::prune(pool));
+    }
+    // `TANKARD_METRICS_ADDR` serves `/metrics`, on the loopback interface unless set
+    let metrics_addr =
+        std::env::var("TANKARD_METRICS_ADDR").unwrap_or_else(|_| "127.0.0.1:3001".to_string());
+    let metrics_listener = TcpListener::bind(metrics_addr).await?;
+    let metrics = metrics_router(hub.clone());
+    tokio::spawn(async move { axum::serve(metrics_listener, metrics).await });
+
+    let app = app(
+        pool,
+        hub,
+        changes,
+        jwt::Keys::load()?,
+        rate_limits,
+        api::ContextConfig::load(),
+    )
+    .await?;
 
     let listener = TcpListener::bind("127.0.0.1:3000").await?;
-    axum::serve(listener, app).await?;
+    // the address of clients is what anonymous requests are rate limited by
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async {
+        _ = tokio::signal::ctrl_c().await;
+    })
+    .await?;
 
     Ok(())
 }
 
-// TODO: fix sse and re-enable tests for it
 #[cfg(test)]
 mod tests {
-    use std::error::Error;
+    use std::{error::Error, str::FromStr};
 
-    use axum::Router;
+    use axum::{body::Body, extract::Request, http::StatusCode, Router};
     use bb8::PooledConnection;
     use bb8_postgres::PostgresConnectionManager;
+    use futures::StreamExt;
+    use http_body_util::BodyExt;
+    use serde_json::json;
     use tokio_postgres::NoTls;
+    use tower::ServiceExt;
 
-    use crate::app;
+    use crate::{
+        api, app, changes,
+        hub::{Hub, Limits},
+        jwt, metrics_router, rate_limits,
+    };
 
     pub(crate) async fn setup_app(
         db_name: &'static str,
@@ -153,11 +393,7 @@ mod tests {
         conn.execute(&format!(r#"create database "{db_name}";"#), &[])
             .await?;
 
-        let manager = PostgresConnectionManager::new_from_stringlike(
-            &format!("postgres://localhost:28817/{db_name}"),
-            NoTls,
-        )?;
-        let pool = Box::leak(Box::new(bb8::Pool::builder().build(manager).await?));
+        let (pool, config) = setup_pool(db_name).await?;
         let conn = pool.get().await?;
         conn.batch_execute(concat!(
             "create extension tankard;",
@@ -166,38 +402,188 @@ mod tests {
         ))
         .await?;
 
-        let app = app(pool).await?;
+        let app = app(
+            pool,
+            Hub::new(config, Limits::default()),
+            changes::CHANNEL,
+            jwt::Keys::default().secret(jwt::tests::SECRET),
+            rate_limits::RateLimits::default(),
+            anonymous(pool).await?,
+        )
+        .await?;
 
         Ok((conn, app))
     }
 
-    // FIXME: test never completes
-    // #[tokio::test]
-    // async fn users_listen() -> Result<(), Box<dyn Error>> {
-    //     let (conn, app) = setup_app("7c1571da-1028-4dc3-b18e-e84842003f10").await?;
-    //     conn.batch_execute(
-    //         "insert into users (username, salt, passhash) values ('one', '', ''), ('two', '', ''), ('three', '', '');",
-    //     ).await?;
-
-    //     let response = app
-    //         .oneshot(
-    //             Request::builder()
-    //                 .uri("/listen/users_event")
-    //                 .header("Accept", "*/*")
-    //                 .body(Body::empty())?,
-    //         )
-    //         .await?;
-
-    //     assert_eq!(response.status(), StatusCode::OK);
-
-    //     conn.batch_execute("insert into users (username, salt, passhash) values ('four', '', '');")
-    //         .await?;
-
-    //     assert_eq!(
-    //         response.into_data_stream().next().await.unwrap()?,
-    //         "event: users_event\ndata: \n\n"
-    //     );
-
-    //     Ok(())
-    // }
+    /// Context config running anonymous requests as the role of the pool.
+    pub(crate) async fn anonymous(
+        pool: &'static bb8::Pool<PostgresConnectionManager<NoTls>>,
+    ) -> Result<api::ContextConfig, Box<dyn Error>> {
+        let role = pool
+            .get()
+            .await?
+            .query_one("select current_user::text;", &[])
+            .await?
+            .get::<_, String>(0);
+        Ok(api::ContextConfig::default().anon_role(&role))
+    }
+
+    /// Another pool for the database of [`setup_app`].
+    pub(crate) async fn setup_pool(
+        db_name: &str,
+    ) -> Result<
+        (
+            &'static bb8::Pool<PostgresConnectionManager<NoTls>>,
+            tokio_postgres::Config,
+        ),
+        Box<dyn Error>,
+    > {
+        let config =
+            tokio_postgres::Config::from_str(&format!("postgres://localhost:28817/{db_name}"))?;
+        let manager = PostgresConnectionManager::new(config.clone(), NoTls);
+        let pool = Box::leak(Box::new(bb8::Pool::builder().build(manager).await?));
+        Ok((pool, config))
+    }
+
+    #[tokio::test]
+    async fn users_listen() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("7c1571da-1028-4dc3-b18e-e84842003f10").await?;
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/listen/users_event")
+                    .header("Accept", "*/*")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        conn.batch_execute(
+            "insert into users (id, username, salt, passhash) values ('00000000-0000-0000-0000-000000000001', 'one', '', ''), ('00000000-0000-0000-0000-000000000002', 'two', '', ''), ('00000000-0000-0000-0000-000000000003', 'three', '', '');",
+        )
+        .await?;
+
+        let mut events = response.into_body().into_data_stream();
+        let data = |event: &[u8]| {
+            let data = std::str::from_utf8(event)
+                .unwrap()
+                .strip_prefix("event: users_event\ndata: ")
+                .unwrap();
+            let mut data = serde_json::from_str::<serde_json::Value>(data).unwrap();
+            if let Some(pks) = data["pks"].as_array_mut() {
+                pks.sort_by_key(|pk| pk.to_string());
+            }
+            data
+        };
+
+        assert_eq!(
+            data(&events.next().await.unwrap()?),
+            json!({ "count": 3, "pks": [
+                "00000000-0000-0000-0000-000000000001",
+                "00000000-0000-0000-0000-000000000002",
+                "00000000-0000-0000-0000-000000000003",
+            ] })
+        );
+
+        // past 100 rows, statements send no primary keys
+        conn.batch_execute(
+            "insert into users (username, salt, passhash) select 'user' || i, '', '' from generate_series(1, 101) i;",
+        )
+        .await?;
+
+        assert_eq!(
+            data(&events.next().await.unwrap()?),
+            json!({ "count": 101, "pks": null })
+        );
+
+        // row level triggers send primary keys
+        conn.batch_execute("select pg_notify('users_event', '2'), pg_notify('users_event', '1');")
+            .await?;
+
+        assert_eq!(
+            data(&events.next().await.unwrap()?),
+            json!({ "count": 2, "pks": ["1", "2"] })
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn listen_unregistered() -> Result<(), Box<dyn Error>> {
+        let (_, app) = setup_app("3f9e2b7a-6d1c-4a85-9e0b-c4d2a7f18e63").await?;
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/listen/tankard_changes")
+                    .header("Accept", "*/*")
+                    .body(Body::empty())?,
+            )
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_listen_role() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("5d8b1e4a-7c2f-4a96-b3e0-e1f9c6a2d457").await?;
+        conn.batch_execute(
+            "do $$ begin create role tankard_listener; exception when duplicate_object or unique_violation then null; end $$; do $$ begin create role tankard_outsider; exception when duplicate_object or unique_violation then null; end $$; select tankard_allow_listen('users_event', 'tankard_listener');",
+        )
+        .await?;
+
+        let listen = |role: &str| {
+            app.clone().oneshot(
+                Request::builder()
+                    .uri("/listen/users_event")
+                    .header("Accept", "*/*")
+                    .header(
+                        "Authorization",
+                        format!("Bearer {}", jwt::tests::token(json!({ "role": role }))),
+                    )
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let response = listen("tankard_listener").await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = listen("tankard_outsider").await?;
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn metrics() -> Result<(), Box<dyn Error>> {
+        let (_, app) = setup_app("b6c3e9d1-2a4f-4d78-8e15-7f0a3c9b2d64").await?;
+
+        let response = app
+            .oneshot(Request::builder().uri("/metrics").body(Body::empty())?)
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let config = tokio_postgres::Config::from_str("postgres://localhost:28817/postgres")?;
+        let hub = Hub::new(config, Limits::default());
+        let _subscription = hub.subscribe("foo").await?;
+
+        let response = metrics_router(hub)
+            .oneshot(Request::builder().uri("/metrics").body(Body::empty())?)
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await?.to_bytes();
+        assert!(std::str::from_utf8(&body)?.contains("\ntankard_subscribers 1\n"));
+
+        Ok(())
+    }
 }
@@ -1,13 +1,14 @@
 use std::str::FromStr;
 
 use winnow::{
-    combinator::{preceded, separated},
+    ascii::float,
+    combinator::{alt, delimited, preceded, rest, separated, separated_pair, seq},
     stream::AsChar,
-    token::take_while,
+    token::{take_till, take_while},
     PResult, Parser,
 };
 
-use crate::api::Select;
+use crate::api::{Filter, Operator, Select};
 
 impl Select {
     fn parse(input: &mut &str) -> PResult<Self> {
@@ -32,11 +33,65 @@ impl FromStr for Select {
     }
 }
 
+/// A finite coordinate, `inf` and `nan` are not.
+fn coordinate(input: &mut &str) -> PResult<f64> {
+    float.verify(|f: &f64| f.is_finite()).parse_next(input)
+}
+
+impl Operator {
+    fn parse(input: &mut &str) -> PResult<Self> {
+        let value = |input: &mut &str| rest.map(str::to_string).parse_next(input);
+        alt((
+            preceded("eq.", value).map(Self::Eq),
+            preceded("neq.", value).map(Self::Neq),
+            preceded("lte.", value).map(Self::Lte),
+            preceded("lt.", value).map(Self::Lt),
+            preceded("gte.", value).map(Self::Gte),
+            preceded("gt.", value).map(Self::Gt),
+            delimited(
+                "in.(",
+                separated(0.., take_till(1.., [',', ')']).map(str::to_string), ","),
+                ")",
+            )
+            .map(Self::In),
+            preceded(
+                "bbox.",
+                separated_pair(
+                    separated_pair(coordinate, ",", coordinate),
+                    ",",
+                    separated_pair(coordinate, ",", coordinate),
+                ),
+            )
+            .map(|((x1, y1), (x2, y2))| Self::Bbox(x1, y1, x2, y2)),
+        ))
+        .parse_next(input)
+    }
+}
+
+impl Filter {
+    fn parse(input: &mut &str) -> PResult<Self> {
+        seq! {Self {
+            column: take_while(1.., |c: char| c.is_alphanum() || c == '_').map(str::to_string),
+            _: "=",
+            operator: Operator::parse,
+        }}
+        .parse_next(input)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse.parse(s).map_err(|e| e.to_string())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::{error::Error, str::FromStr};
 
-    use crate::api::Select;
+    use crate::api::{Filter, Operator, Select};
 
     #[test]
     fn single() -> Result<(), Box<dyn Error>> {
@@ -58,4 +113,79 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn filter_eq() -> Result<(), Box<dyn Error>> {
+        let filter = Filter::from_str("username=eq.one two")?;
+
+        assert_eq!(
+            Filter {
+                column: "username".to_string(),
+                operator: Operator::Eq("one two".to_string())
+            },
+            filter
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter_in() -> Result<(), Box<dyn Error>> {
+        let filter = Filter::from_str("username=in.(one,two)")?;
+
+        assert_eq!(
+            Filter {
+                column: "username".to_string(),
+                operator: Operator::In(vec!["one".to_string(), "two".to_string()])
+            },
+            filter
+        );
+        assert_eq!(
+            Filter::from_str("username=in.()")?.operator,
+            Operator::In(vec![])
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter_lte() -> Result<(), Box<dyn Error>> {
+        let filter = Filter::from_str("added=lte.2024-01-01")?;
+
+        assert_eq!(
+            Filter {
+                column: "added".to_string(),
+                operator: Operator::Lte("2024-01-01".to_string())
+            },
+            filter
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter_bbox() -> Result<(), Box<dyn Error>> {
+        let filter = Filter::from_str("geom=bbox.-1.5,2,3.25,4")?;
+
+        assert_eq!(
+            Filter {
+                column: "geom".to_string(),
+                operator: Operator::Bbox(-1.5, 2.0, 3.25, 4.0)
+            },
+            filter
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter_bbox_not_finite() {
+        assert!(Filter::from_str("geom=bbox.0,0,inf,1").is_err());
+        assert!(Filter::from_str("geom=bbox.nan,0,1,1").is_err());
+    }
+
+    #[test]
+    fn filter_unknown_operator() {
+        assert!(Filter::from_str("username=like.one").is_err());
+    }
 }
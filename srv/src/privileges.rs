@@ -0,0 +1,85 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use axum::http::StatusCode;
+
+use crate::{
+    api::{Column, Filters, Transaction},
+    internal_error,
+};
+
+pub(crate) type Columns = Arc<HashMap<String, HashSet<String>>>;
+
+/// Columns each role may select, loaded the first time a role is seen.
+// TODO: refresh along with the schema
+#[derive(Debug, Default)]
+pub(crate) struct Privileges(Mutex<HashMap<Option<String>, Columns>>);
+
+impl Privileges {
+    /// Columns the role of `conn` may select, by table.
+    pub(crate) async fn columns(
+        &self,
+        conn: &Transaction,
+    ) -> Result<Columns, tokio_postgres::Error> {
+        if let Some(columns) = self.0.lock().unwrap().get(&conn.role) {
+            return Ok(columns.clone());
+        }
+        let columns = Arc::new(
+            conn.query(include_str!("../sql/schema_column_privileges.sql"), &[])
+                .await?
+                .into_iter()
+                .map(|row| {
+                    let columns = serde_json::from_value(row.get("columns")).unwrap_or_default();
+                    (row.get("table_name"), columns)
+                })
+                .collect::<HashMap<_, _>>(),
+        );
+        self.0
+            .lock()
+            .unwrap()
+            .insert(conn.role.clone(), columns.clone());
+        Ok(columns)
+    }
+
+    /// Check `select` and the filtered columns against what the role of `conn` may select, an
+    /// empty `select` becomes all of those columns but hidden ones.
+    pub(crate) async fn authorize(
+        &self,
+        conn: &Transaction,
+        table: &str,
+        columns: &[Column],
+        select: Vec<String>,
+        filters: &Filters,
+    ) -> Result<Vec<String>, (StatusCode, String)> {
+        let privileges = self.columns(conn).await.map_err(internal_error)?;
+        let allowed = privileges.get(table).filter(|allowed| !allowed.is_empty());
+        let Some(allowed) = allowed else {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("permission denied for table {table}"),
+            ));
+        };
+
+        let forbidden = select
+            .iter()
+            .chain(filters.0.iter().map(|filter| &filter.column))
+            .find(|column| !allowed.contains(*column));
+        if let Some(column) = forbidden {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("permission denied for column {column} of table {table}"),
+            ));
+        }
+
+        if !select.is_empty() {
+            return Ok(select);
+        }
+        Ok(columns
+            .iter()
+            .filter(|column| !column.hidden && allowed.contains(&column.column_name))
+            .map(|column| column.column_name.clone())
+            .collect())
+    }
+}
@@ -0,0 +1,310 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header::RETRY_AFTER, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use bb8_postgres::PostgresConnectionManager;
+use tokio_postgres::NoTls;
+
+use crate::{api_keys::ApiKey, auth::User, internal_error, AppState};
+
+/// In-memory buckets kept at most, full ones are dropped past this.
+const BUCKETS: usize = 100_000;
+const PRUNE: Duration = Duration::from_secs(60 * 60);
+
+/// `capacity` requests, refilled over `period`, to paths starting with `path`.
+#[derive(Debug)]
+struct Rule {
+    /// `None` for any method.
+    method: Option<Method>,
+    path: String,
+    capacity: f64,
+    period: Duration,
+}
+
+impl Rule {
+    fn per_second(&self) -> f64 {
+        self.capacity / self.period.as_secs_f64()
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket limits per client, by API key, user or IP address.
+#[derive(Debug, Default)]
+pub(crate) struct RateLimits {
+    rules: Vec<Rule>,
+    /// Buckets are kept in `tankard_rate_limits`, shared by all instances.
+    pub(crate) postgres: bool,
+    buckets: Mutex<HashMap<(usize, String), Bucket>>,
+}
+
+impl RateLimits {
+    /// Rules of `TANKARD_RATE_LIMITS`, like `GET /api=100/60,/auth/login=5/60` for 100 reads of
+    /// the API and 5 logins a minute, kept in Postgres with `TANKARD_RATE_LIMIT_STORE=postgres`.
+    pub(crate) fn load() -> Result<Self, Box<dyn Error>> {
+        let mut limits = Self::parse(&std::env::var("TANKARD_RATE_LIMITS").unwrap_or_default())?;
+        if std::env::var("TANKARD_RATE_LIMIT_STORE").is_ok_and(|store| store == "postgres") {
+            limits = limits.postgres();
+        }
+        Ok(limits)
+    }
+
+    /// Rules like `TANKARD_RATE_LIMITS` has them. Buckets that never fill up would never let a
+    /// request through, so capacities and periods of 0 are rejected.
+    fn parse(rules: &str) -> Result<Self, Box<dyn Error>> {
+        let mut limits = Self::default();
+        for rule in rules.split(',').filter(|rule| !rule.trim().is_empty()) {
+            let (route, limit) = rule
+                .split_once('=')
+                .ok_or_else(|| format!("rate limit {rule} is not route=capacity/seconds"))?;
+            let (method, path) = match route.trim().split_once(' ') {
+                Some((method, path)) => (Some(method.parse()?), path),
+                None => (None, route.trim()),
+            };
+            let (capacity, seconds) = limit
+                .split_once('/')
+                .ok_or_else(|| format!("rate limit {rule} is not route=capacity/seconds"))?;
+            let (capacity, seconds) = (capacity.trim().parse()?, seconds.trim().parse()?);
+            if capacity == 0 || seconds == 0 {
+                return Err(format!("rate limit {rule} has no capacity or period").into());
+            }
+            limits = limits.rule(method, path, capacity, Duration::from_secs(seconds));
+        }
+        Ok(limits)
+    }
+
+    /// Requests matching more than one rule are limited by the first.
+    pub(crate) fn rule(
+        mut self,
+        method: Option<Method>,
+        path: &str,
+        capacity: u32,
+        period: Duration,
+    ) -> Self {
+        self.rules.push(Rule {
+            method,
+            path: path.to_string(),
+            capacity: capacity.into(),
+            period,
+        });
+        self
+    }
+
+    pub(crate) fn postgres(mut self) -> Self {
+        self.postgres = true;
+        self
+    }
+
+    /// Take a token from the in-memory bucket, `None` when there was one, or else how long until
+    /// there is.
+    fn take(&self, rule: usize, client: String) -> Option<Duration> {
+        let Rule { capacity, .. } = self.rules[rule];
+        let per_second = self.rules[rule].per_second();
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= BUCKETS {
+            buckets.retain(|(rule, _), bucket| {
+                let Rule { capacity, .. } = self.rules[*rule];
+                let per_second = self.rules[*rule].per_second();
+                bucket.tokens + (now - bucket.updated).as_secs_f64() * per_second < capacity
+            });
+        }
+        let bucket = buckets.entry((rule, client)).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let available =
+            capacity.min(bucket.tokens + (now - bucket.updated).as_secs_f64() * per_second);
+        bucket.updated = now;
+        if available >= 1.0 {
+            bucket.tokens = available - 1.0;
+            None
+        } else {
+            bucket.tokens = available;
+            Some(Duration::from_secs_f64((1.0 - available) / per_second))
+        }
+    }
+}
+
+/// Delete buckets of `tankard_rate_limits` idle long enough to be full every so often.
+pub(crate) async fn prune(pool: &'static bb8::Pool<PostgresConnectionManager<NoTls>>) {
+    let mut interval = tokio::time::interval(PRUNE);
+    loop {
+        interval.tick().await;
+        if let Ok(conn) = pool.get().await {
+            _ = conn
+                .execute("select tankard_rate_limit_prune();", &[])
+                .await;
+        }
+    }
+}
+
+/// Who a request counts against.
+fn client(request: &Request, addr: Option<SocketAddr>) -> String {
+    if let Some(key) = request.extensions().get::<ApiKey>() {
+        format!("key:{}", key.name)
+    } else if let Some(user) = request.extensions().get::<User>() {
+        format!("user:{}", user.id)
+    } else {
+        addr.map_or("ip:unknown".to_string(), |addr| format!("ip:{}", addr.ip()))
+    }
+}
+
+/// Turn away requests over the limit of their route with `429 Too Many Requests`.
+pub(crate) async fn limit(
+    State(AppState {
+        pool, rate_limits, ..
+    }): State<AppState>,
+    addr: Option<ConnectInfo<SocketAddr>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let rule = rate_limits.rules.iter().position(|rule| {
+        rule.method.as_ref().is_none_or(|m| m == request.method())
+            && request.uri().path().starts_with(&rule.path)
+    });
+    let Some(rule) = rule else {
+        return next.run(request).await;
+    };
+    let client = client(&request, addr.map(|ConnectInfo(addr)| addr));
+
+    let wait = if rate_limits.postgres {
+        let Rule { method, path, .. } = &rate_limits.rules[rule];
+        let method = method.as_ref().map_or("*", Method::as_str);
+        let conn = match pool.get().await.map_err(internal_error) {
+            Ok(conn) => conn,
+            Err(err) => return err.into_response(),
+        };
+        match conn
+            .query_one(
+                "select tankard_rate_limit_take($1, $2, $3);",
+                &[
+                    &format!("{method} {path} {client}"),
+                    &rate_limits.rules[rule].capacity,
+                    &rate_limits.rules[rule].per_second(),
+                ],
+            )
+            .await
+        {
+            Ok(row) => Some(Duration::from_secs_f64(row.get(0))).filter(|wait| !wait.is_zero()),
+            Err(err) => return internal_error(err).into_response(),
+        }
+    } else {
+        rate_limits.take(rule, client)
+    };
+
+    match wait {
+        None => next.run(request).await,
+        Some(wait) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, wait.as_secs_f64().ceil().max(1.0).to_string())],
+            "rate limit exceeded",
+        )
+            .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, time::Duration};
+
+    use axum::{
+        body::Body,
+        extract::Request,
+        http::{
+            header::{ACCEPT, RETRY_AFTER},
+            Method, StatusCode,
+        },
+    };
+    use tower::ServiceExt;
+
+    use super::RateLimits;
+    use crate::{
+        changes,
+        hub::{Hub, Limits},
+        jwt::Keys,
+        tests::{anonymous, setup_app, setup_pool},
+    };
+
+    async fn limited(db_name: &'static str, limits: RateLimits) -> Result<(), Box<dyn Error>> {
+        let (_, _) = setup_app(db_name).await?;
+        let (pool, config) = setup_pool(db_name).await?;
+        let app = crate::app(
+            pool,
+            Hub::new(config, Limits::default()),
+            changes::CHANNEL,
+            Keys::default(),
+            limits.rule(Some(Method::GET), "/api", 2, Duration::from_secs(60)),
+            anonymous(pool).await?,
+        )
+        .await?;
+
+        let get = || {
+            app.clone().oneshot(
+                Request::builder()
+                    .uri("/api/users?select=username")
+                    .header(ACCEPT, "text/csv")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        assert_eq!(get().await?.status(), StatusCode::OK);
+        assert_eq!(get().await?.status(), StatusCode::OK);
+
+        let response = get().await?;
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "30");
+
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri("/auth/me").body(Body::empty())?)
+            .await?;
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rules() {
+        assert!(RateLimits::parse("GET /api=100/60, /auth/login=5/60")
+            .is_ok_and(|limits| limits.rules.len() == 2));
+        assert!(RateLimits::parse("/api=0/60").is_err());
+        assert!(RateLimits::parse("/api=100/0").is_err());
+    }
+
+    #[tokio::test]
+    async fn users_rate_limit() -> Result<(), Box<dyn Error>> {
+        limited(
+            "c5a8e3f1-6d2b-4b97-9a40-e1f7c2d8b356",
+            RateLimits::default(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn users_rate_limit_postgres() -> Result<(), Box<dyn Error>> {
+        limited(
+            "8e1d4b7a-3c9f-4e62-b5d0-a7f2c6e9d413",
+            RateLimits::default().postgres(),
+        )
+        .await
+    }
+}
@@ -0,0 +1,496 @@
+use std::{collections::HashMap, time::Duration};
+
+use bb8::PooledConnection;
+use bb8_postgres::PostgresConnectionManager;
+use serde_json::{json, Map, Value};
+use tokio_postgres::{
+    types::{PgLsn, Type},
+    NoTls,
+};
+
+use crate::hub::Hub;
+
+/// Hub channel decoded changes are published on, in the shape of `tankard_changes` notifications.
+pub(crate) const CHANNEL: &str = "tankard_replication";
+/// Name of the publication, which the slots of every instance share.
+const PUBLICATION: &str = "tankard";
+const POLL: Duration = Duration::from_millis(200);
+/// Changes read from the slot at a time, it stops at the first commit after as many.
+const BATCH: i32 = 10_000;
+
+type Connection = PooledConnection<'static, PostgresConnectionManager<NoTls>>;
+
+/// Name of the slot of this instance, `TANKARD_REPLICATION_SLOT` or `tankard`.
+///
+/// Every instance needs a slot of its own, changes read from a slot are gone for anyone else
+/// reading it. Slots are kept when an instance stops, so it resumes where it left off; drop the
+/// slot of an instance that is retired with `pg_drop_replication_slot`, or the server keeps WAL
+/// around for it.
+pub(crate) fn slot() -> String {
+    std::env::var("TANKARD_REPLICATION_SLOT").unwrap_or_else(|_| "tankard".to_string())
+}
+
+/// A connection holding the advisory lock of `slot`, `None` while another instance holds it.
+async fn lock(
+    pool: &'static bb8::Pool<PostgresConnectionManager<NoTls>>,
+    slot: &str,
+) -> Option<Connection> {
+    let conn = pool.get_owned().await.ok()?;
+    conn.query_one(
+        "select pg_try_advisory_lock(hashtext('tankard_replication'), hashtext($1));",
+        &[&slot],
+    )
+    .await
+    .ok()?
+    .get::<_, bool>(0)
+    .then_some(conn)
+}
+
+/// Create the publication and the `pgoutput` slot, unless they exist, and lock the slot for this
+/// instance. Fails when another instance reads it.
+///
+/// The publication lists the tables of the `public` schema the connecting role owns, leaving out
+/// the `tankard_` ones, so it needs no superuser; creating the slot needs the `replication`
+/// attribute though. Tables created later are added on the next start.
+pub(crate) async fn start(
+    pool: &'static bb8::Pool<PostgresConnectionManager<NoTls>>,
+    slot: &str,
+) -> Result<Connection, Box<dyn std::error::Error>> {
+    let conn = pool.get().await?;
+    // slots cannot be created in a transaction that has written, so this takes two round trips
+    conn.batch_execute(&format!(
+        r"do $$
+        declare
+            tables text := (select string_agg(format('%I', tablename), ', ') from pg_tables where schemaname = 'public' and tableowner = current_user and tablename not like 'tankard\_%');
+        begin
+            if not exists (select from pg_publication where pubname = '{PUBLICATION}') then
+                execute 'create publication {PUBLICATION}';
+            end if;
+            if tables is not null then
+                execute format('alter publication {PUBLICATION} set table %s', tables);
+            end if;
+        end $$;"
+    ))
+    .await?;
+    conn.execute(
+        "select pg_create_logical_replication_slot($1, 'pgoutput') where not exists (select from pg_replication_slots where slot_name = $1);",
+        &[&slot],
+    )
+    .await?;
+    lock(pool, slot).await.ok_or_else(|| {
+        format!("replication slot {slot} is read by another instance, set TANKARD_REPLICATION_SLOT")
+            .into()
+    })
+}
+
+/// Consume the slot and publish its inserts, updates and deletes to the hub.
+///
+/// `tokio-postgres` does not speak the streaming replication protocol, so the slot is read with
+/// `pg_logical_slot_get_binary_changes`, which hands out the same `pgoutput` messages. It returns
+/// them all at once, so a backlog is read [`BATCH`] changes at a time until the slot is drained;
+/// a single transaction is never split though.
+///
+/// `conn` holds the lock of `slot` from [`start`], it is taken again when the connection is lost.
+pub(crate) async fn run(
+    pool: &'static bb8::Pool<PostgresConnectionManager<NoTls>>,
+    hub: Hub,
+    slot: String,
+    conn: Connection,
+) {
+    let mut relations = HashMap::new();
+    let mut interval = tokio::time::interval(POLL);
+    let mut locked = Some(conn);
+    loop {
+        interval.tick().await;
+        let conn = match locked.take() {
+            Some(conn) if !conn.is_closed() => conn,
+            _ => match lock(pool, &slot).await {
+                Some(conn) => conn,
+                None => continue,
+            },
+        };
+        loop {
+            let Ok(rows) = conn
+                .query(
+                    &format!("select lsn, data from pg_logical_slot_get_binary_changes($1, null, $2, 'proto_version', '1', 'publication_names', '{PUBLICATION}');"),
+                    &[&slot, &BATCH],
+                )
+                .await
+            else {
+                break;
+            };
+            let drained = rows.len() < BATCH as usize;
+            for row in rows {
+                let lsn = u64::from(row.get::<_, PgLsn>(0));
+                let Some(message) = decode(row.get(1)) else {
+                    continue;
+                };
+                if let Some(change) = change(&mut relations, lsn, message) {
+                    hub.publish(CHANNEL, &change.to_string());
+                }
+            }
+            if drained {
+                break;
+            }
+        }
+        locked = Some(conn);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Relation {
+    name: String,
+    /// Name, type and whether the column is part of the replica identity.
+    columns: Vec<(String, u32, bool)>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Datum {
+    Null,
+    /// TOASTed value that was not changed, and not sent.
+    Unchanged,
+    Text(String),
+}
+
+#[derive(Debug, PartialEq)]
+enum Message {
+    Relation(u32, Relation),
+    Insert(u32, Vec<Datum>),
+    Update(u32, Option<Vec<Datum>>, Vec<Datum>),
+    Delete(u32, Vec<Datum>),
+    /// Begin, commit and the messages we have no use for.
+    Other,
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        let bytes = self.0.get(..n)?;
+        self.0 = &self.0[n..];
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let end = self.0.iter().position(|&b| b == 0)?;
+        let s = String::from_utf8(self.bytes(end)?.to_vec()).ok();
+        self.bytes(1)?;
+        s
+    }
+
+    fn tuple(&mut self) -> Option<Vec<Datum>> {
+        (0..self.u16()?)
+            .map(|_| match self.u8()? {
+                b'n' => Some(Datum::Null),
+                b'u' => Some(Datum::Unchanged),
+                b't' => {
+                    let len = self.u32()? as usize;
+                    String::from_utf8(self.bytes(len)?.to_vec())
+                        .ok()
+                        .map(Datum::Text)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Decode a `pgoutput` message of protocol version 1.
+fn decode(data: &[u8]) -> Option<Message> {
+    let mut r = Reader(data);
+    match r.u8()? {
+        b'R' => {
+            let oid = r.u32()?;
+            let _namespace = r.string()?;
+            let name = r.string()?;
+            let _identity = r.u8()?;
+            let columns = (0..r.u16()?)
+                .map(|_| {
+                    let key = r.u8()? & 1 == 1;
+                    let name = r.string()?;
+                    let type_oid = r.u32()?;
+                    let _typmod = r.u32()?;
+                    Some((name, type_oid, key))
+                })
+                .collect::<Option<_>>()?;
+            Some(Message::Relation(oid, Relation { name, columns }))
+        }
+        b'I' => {
+            let oid = r.u32()?;
+            (r.u8()? == b'N').then_some(())?;
+            Some(Message::Insert(oid, r.tuple()?))
+        }
+        b'U' => {
+            let oid = r.u32()?;
+            let (old, new) = match r.u8()? {
+                b'K' | b'O' => {
+                    let old = r.tuple()?;
+                    (r.u8()? == b'N').then_some(())?;
+                    (Some(old), r.tuple()?)
+                }
+                b'N' => (None, r.tuple()?),
+                _ => return None,
+            };
+            Some(Message::Update(oid, old, new))
+        }
+        b'D' => {
+            let oid = r.u32()?;
+            matches!(r.u8()?, b'K' | b'O').then_some(())?;
+            Some(Message::Delete(oid, r.tuple()?))
+        }
+        _ => Some(Message::Other),
+    }
+}
+
+impl Relation {
+    /// JSON object of a tuple, leaving out unchanged TOASTed values.
+    fn row(&self, tuple: &[Datum]) -> Map<String, Value> {
+        self.columns
+            .iter()
+            .zip(tuple)
+            .filter_map(|((name, type_oid, _), datum)| {
+                let value = match datum {
+                    Datum::Null => Value::Null,
+                    Datum::Unchanged => return None,
+                    Datum::Text(s)
+                        if *type_oid == Type::JSON.oid() || *type_oid == Type::JSONB.oid() =>
+                    {
+                        serde_json::from_str(s).unwrap_or_else(|_| Value::String(s.clone()))
+                    }
+                    Datum::Text(s) => Value::String(s.clone()),
+                };
+                Some((name.clone(), value))
+            })
+            .collect()
+    }
+
+    /// Names of the unchanged TOASTed values of a tuple.
+    fn unchanged(&self, tuple: &[Datum]) -> Vec<String> {
+        self.columns
+            .iter()
+            .zip(tuple)
+            .filter(|(_, datum)| **datum == Datum::Unchanged)
+            .map(|((name, _, _), _)| name.clone())
+            .collect()
+    }
+
+    fn pk(&self, row: &Map<String, Value>) -> Map<String, Value> {
+        self.columns
+            .iter()
+            .filter(|(_, _, key)| *key)
+            .filter_map(|(name, _, _)| Some((name.clone(), row.get(name)?.clone())))
+            .collect()
+    }
+}
+
+/// Turn a decoded message into a change payload, remembering relations as they come by.
+fn change(relations: &mut HashMap<u32, Relation>, lsn: u64, message: Message) -> Option<Value> {
+    let (op, oid, old, new) = match message {
+        Message::Relation(oid, relation) => {
+            relations.insert(oid, relation);
+            return None;
+        }
+        Message::Insert(oid, new) => ("insert", oid, None, Some(new)),
+        Message::Update(oid, old, new) => ("update", oid, old, Some(new)),
+        Message::Delete(oid, old) => ("delete", oid, Some(old), None),
+        Message::Other => return None,
+    };
+    let relation = relations.get(&oid)?;
+    let old = old.map(|old| relation.row(&old));
+    let mut unchanged = Vec::new();
+    let new = new.map(|new| {
+        let mut row = relation.row(&new);
+        // unchanged TOASTed values are only sent with the old tuple, under `replica identity full`
+        for name in relation.unchanged(&new) {
+            match old.as_ref().and_then(|old| old.get(&name)) {
+                Some(value) => _ = row.insert(name, value.clone()),
+                None => unchanged.push(name),
+            }
+        }
+        row
+    });
+    let pk = relation.pk(new.as_ref().or(old.as_ref())?);
+    let mut change = json!({
+        "id": lsn,
+        "op": op,
+        "table": relation.name,
+        "pk": pk,
+        "old": old,
+        "new": new,
+    });
+    // columns left out of `new`, for clients to tell them from ones that were removed
+    if !unchanged.is_empty() {
+        change["unchanged"] = json!(unchanged);
+    }
+    Some(change)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::{change, decode, Datum, Message, Relation};
+
+    fn relation() -> Vec<u8> {
+        let mut data = b"R".to_vec();
+        data.extend(16384u32.to_be_bytes());
+        data.extend(b"public\0users\0d");
+        data.extend(2u16.to_be_bytes());
+        data.extend(b"\x01id\0");
+        data.extend(2950u32.to_be_bytes());
+        data.extend((-1i32).to_be_bytes());
+        data.extend(b"\x00username\0");
+        data.extend(25u32.to_be_bytes());
+        data.extend((-1i32).to_be_bytes());
+        data
+    }
+
+    fn tuple(values: &[Option<&str>]) -> Vec<u8> {
+        let mut data = (values.len() as u16).to_be_bytes().to_vec();
+        for value in values {
+            match value {
+                Some(value) => {
+                    data.push(b't');
+                    data.extend((value.len() as u32).to_be_bytes());
+                    data.extend(value.as_bytes());
+                }
+                None => data.push(b'n'),
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn decode_relation() {
+        assert_eq!(
+            decode(&relation()),
+            Some(Message::Relation(
+                16384,
+                Relation {
+                    name: "users".to_string(),
+                    columns: vec![
+                        ("id".to_string(), 2950, true),
+                        ("username".to_string(), 25, false)
+                    ],
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn decode_update() {
+        let mut data = b"U".to_vec();
+        data.extend(16384u32.to_be_bytes());
+        data.push(b'K');
+        data.extend(tuple(&[Some("1"), None]));
+        data.push(b'N');
+        data.extend(tuple(&[Some("2"), Some("two")]));
+
+        assert_eq!(
+            decode(&data),
+            Some(Message::Update(
+                16384,
+                Some(vec![Datum::Text("1".to_string()), Datum::Null]),
+                vec![Datum::Text("2".to_string()), Datum::Text("two".to_string())]
+            ))
+        );
+    }
+
+    #[test]
+    fn decode_truncated() {
+        let mut data = b"I".to_vec();
+        data.extend(16384u32.to_be_bytes());
+        data.push(b'N');
+        data.extend(&tuple(&[Some("1"), Some("one")])[..8]);
+
+        assert_eq!(decode(&data), None);
+    }
+
+    #[test]
+    fn change_insert() {
+        let mut relations = HashMap::new();
+        assert_eq!(
+            change(&mut relations, 1, decode(&relation()).unwrap()),
+            None
+        );
+
+        let mut data = b"I".to_vec();
+        data.extend(16384u32.to_be_bytes());
+        data.push(b'N');
+        data.extend(tuple(&[Some("1"), Some("one")]));
+
+        assert_eq!(
+            change(&mut relations, 2, decode(&data).unwrap()),
+            Some(json!({
+                "id": 2,
+                "op": "insert",
+                "table": "users",
+                "pk": { "id": "1" },
+                "old": null,
+                "new": { "id": "1", "username": "one" },
+            }))
+        );
+    }
+
+    #[test]
+    fn change_update_unchanged() {
+        let mut relations = HashMap::new();
+        change(&mut relations, 1, decode(&relation()).unwrap());
+
+        let mut data = b"U".to_vec();
+        data.extend(16384u32.to_be_bytes());
+        data.push(b'N');
+        data.extend(2u16.to_be_bytes());
+        data.extend(&tuple(&[Some("1")])[2..]);
+        data.push(b'u');
+
+        assert_eq!(
+            change(&mut relations, 2, decode(&data).unwrap()),
+            Some(json!({
+                "id": 2,
+                "op": "update",
+                "table": "users",
+                "pk": { "id": "1" },
+                "old": null,
+                "new": { "id": "1" },
+                "unchanged": ["username"],
+            }))
+        );
+
+        let mut data = b"U".to_vec();
+        data.extend(16384u32.to_be_bytes());
+        data.push(b'O');
+        data.extend(tuple(&[Some("1"), Some("one")]));
+        data.push(b'N');
+        data.extend(2u16.to_be_bytes());
+        data.extend(&tuple(&[Some("2")])[2..]);
+        data.push(b'u');
+
+        assert_eq!(
+            change(&mut relations, 3, decode(&data).unwrap()),
+            Some(json!({
+                "id": 3,
+                "op": "update",
+                "table": "users",
+                "pk": { "id": "2" },
+                "old": { "id": "1", "username": "one" },
+                "new": { "id": "2", "username": "one" },
+            }))
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use chrono::{NaiveDate, NaiveDateTime};
+use rust_xlsxwriter::{Format, Workbook, XlsxError};
+use spreadsheet_ods::{OdsError, Sheet, WorkBook};
+use tokio_postgres::Row;
+
+/// How a column is selected and written as a typed spreadsheet cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Cell {
+    /// Types a spreadsheet number holds exactly.
+    Number,
+    /// Types a spreadsheet number may hold only some values of.
+    Numeric,
+    Bool,
+    Date,
+    Timestamp,
+    TimestampTz,
+    Text,
+}
+
+impl Cell {
+    pub(crate) fn of(data_type: &str) -> Self {
+        match data_type {
+            "smallint" | "integer" | "real" | "double precision" => Self::Number,
+            "bigint" | "numeric" => Self::Numeric,
+            "boolean" => Self::Bool,
+            "date" => Self::Date,
+            "timestamp without time zone" => Self::Timestamp,
+            "timestamp with time zone" => Self::TimestampTz,
+            _ => Self::Text,
+        }
+    }
+
+    /// Select expressions yielding a value `write` knows how to read, null when the value does
+    /// not fit the cell type, and the value as text, written instead then.
+    pub(crate) fn select(self, column: &str) -> String {
+        let value = match self {
+            Self::Number => format!(
+                "case when {column}::float8 not in ('NaN', 'Infinity', '-Infinity') then {column}::float8 end"
+            ),
+            // NaN and infinite values are not less than anything
+            Self::Numeric => format!(
+                "case when not abs({column}::numeric) < 1e300 then null when {column}::numeric = {column}::float8::numeric then {column}::float8 end"
+            ),
+            Self::Bool | Self::Date | Self::Timestamp => column.to_string(),
+            // spreadsheets have no time zones, so export timestamps as UTC
+            Self::TimestampTz => format!("{column} at time zone 'UTC'"),
+            Self::Text => "null".to_string(),
+        };
+        format!("{value}, {column}::text")
+    }
+}
+
+/// Excel limits sheet names to 31 characters.
+fn sheet_name(name: &str) -> String {
+    name.chars().take(31).collect()
+}
+
+pub(crate) fn xlsx(name: &str, head: &[(&str, Cell)], rows: &[Row]) -> Result<Vec<u8>, XlsxError> {
+    let mut workbook = Workbook::new();
+    let sheet = workbook.add_worksheet().set_name(sheet_name(name))?;
+
+    let bold = Format::new().set_bold();
+    let date = Format::new().set_num_format("yyyy-mm-dd");
+    let timestamp = Format::new().set_num_format("yyyy-mm-dd hh:mm:ss");
+
+    for (c, (column, _)) in head.iter().enumerate() {
+        sheet.write_string_with_format(0, c as u16, *column, &bold)?;
+    }
+    sheet.set_freeze_panes(1, 0)?;
+
+    for (r, row) in rows.iter().enumerate() {
+        let r = r as u32 + 1;
+        for (c, (_, cell)) in head.iter().enumerate() {
+            let (i, c) = (2 * c, c as u16);
+            match cell {
+                Cell::Number | Cell::Numeric => {
+                    if let Ok(Some(v)) = row.try_get::<_, Option<f64>>(i) {
+                        sheet.write_number(r, c, v)?;
+                        continue;
+                    }
+                }
+                Cell::Bool => {
+                    if let Ok(Some(v)) = row.try_get::<_, Option<bool>>(i) {
+                        sheet.write_boolean(r, c, v)?;
+                        continue;
+                    }
+                }
+                // `infinity` does not decode
+                Cell::Date => {
+                    if let Ok(Some(v)) = row.try_get::<_, Option<NaiveDate>>(i) {
+                        sheet.write_datetime_with_format(r, c, v, &date)?;
+                        continue;
+                    }
+                }
+                Cell::Timestamp | Cell::TimestampTz => {
+                    if let Ok(Some(v)) = row.try_get::<_, Option<NaiveDateTime>>(i) {
+                        sheet.write_datetime_with_format(r, c, v, &timestamp)?;
+                        continue;
+                    }
+                }
+                Cell::Text => {}
+            }
+            if let Some(v) = row.get::<_, Option<String>>(i + 1) {
+                sheet.write_string(r, c, v)?;
+            }
+        }
+    }
+
+    workbook.save_to_buffer()
+}
+
+pub(crate) fn ods(name: &str, head: &[(&str, Cell)], rows: &[Row]) -> Result<Vec<u8>, OdsError> {
+    let mut workbook = WorkBook::new_empty();
+    let mut sheet = Sheet::new(sheet_name(name));
+
+    for (c, (column, _)) in head.iter().enumerate() {
+        sheet.set_value(0, c as u32, *column);
+    }
+    sheet.split_row_header(0);
+
+    for (r, row) in rows.iter().enumerate() {
+        let r = r as u32 + 1;
+        for (c, (_, cell)) in head.iter().enumerate() {
+            let (i, c) = (2 * c, c as u32);
+            match cell {
+                Cell::Number | Cell::Numeric => {
+                    if let Ok(Some(v)) = row.try_get::<_, Option<f64>>(i) {
+                        sheet.set_value(r, c, v);
+                        continue;
+                    }
+                }
+                Cell::Bool => {
+                    if let Ok(Some(v)) = row.try_get::<_, Option<bool>>(i) {
+                        sheet.set_value(r, c, v);
+                        continue;
+                    }
+                }
+                Cell::Date => {
+                    if let Ok(Some(v)) = row.try_get::<_, Option<NaiveDate>>(i) {
+                        sheet.set_value(r, c, v);
+                        continue;
+                    }
+                }
+                Cell::Timestamp | Cell::TimestampTz => {
+                    if let Ok(Some(v)) = row.try_get::<_, Option<NaiveDateTime>>(i) {
+                        sheet.set_value(r, c, v);
+                        continue;
+                    }
+                }
+                Cell::Text => {}
+            }
+            if let Some(v) = row.get::<_, Option<String>>(i + 1) {
+                sheet.set_value(r, c, v);
+            }
+        }
+    }
+
+    workbook.push_sheet(sheet);
+    spreadsheet_ods::write_ods_buf(&mut workbook, Vec::new())
+}
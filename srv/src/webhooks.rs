@@ -0,0 +1,496 @@
+use std::{collections::HashMap, time::Duration};
+
+use axum::http::{header::CONTENT_TYPE, StatusCode};
+use bb8_postgres::PostgresConnectionManager;
+use futures::{FutureExt, StreamExt};
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use tokio_postgres::NoTls;
+
+use crate::{
+    api::{Column, Context, Filters, Select, Transaction},
+    changes::{read, Cursor, Feed, POLL},
+    hub::Subscription,
+    internal_error,
+    jobs::Job,
+    AppState,
+};
+
+pub(crate) const SIGNATURE: &str = "X-Tankard-Signature";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Job kind of webhook deliveries.
+pub(crate) const JOB: &str = "webhook";
+/// Attempts at a delivery before it is dead-lettered, backing off as jobs do.
+const ATTEMPTS: i32 = 5;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Webhook {
+    pub(crate) id: i64,
+    pub(crate) url: String,
+    pub(crate) secret: String,
+}
+
+/// `sha256=` and the hex HMAC-SHA256 of `body` keyed with `secret`.
+pub(crate) fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// How far a round of [`dispatch`] got.
+enum Dispatched {
+    /// Changes were read, more may follow.
+    Some,
+    /// None could be read yet, they are held back behind older transactions or another instance
+    /// is at it.
+    Held,
+    /// None are left.
+    None,
+}
+
+/// Queue deliveries of the outbox changes after `tankard_webhook_cursor` to the webhooks of
+/// `tankard_webhooks` that match them, moving the cursor past them.
+///
+/// One instance at a time dispatches, in a transaction with the deliveries it queues, so a change
+/// is dispatched once however many instances run and whenever they ran.
+async fn dispatch(
+    tables: &HashMap<String, Vec<Column>>,
+    state: &AppState,
+) -> Result<Dispatched, (StatusCode, String)> {
+    // webhooks see changes as the role of the pool
+    let conn = Transaction::begin(state.pool, &Context::default()).await?;
+    let locked = conn
+        .query_one(
+            "select pg_try_advisory_xact_lock(hashtext('tankard_webhook_cursor'));",
+            &[],
+        )
+        .await
+        .map_err(internal_error)?
+        .get::<_, bool>(0);
+    if !locked {
+        return Ok(Dispatched::Held);
+    }
+    let row = conn
+        .query_one(
+            "select xid::text::bigint, id from tankard_webhook_cursor;",
+            &[],
+        )
+        .await
+        .map_err(internal_error)?;
+    let cursor = Cursor {
+        xid: row.get(0),
+        id: row.get(1),
+    };
+    let (changes, held) = read(&conn, None, cursor).await?;
+    let Some(&(cursor, _)) = changes.last() else {
+        return Ok(if held {
+            Dispatched::Held
+        } else {
+            Dispatched::None
+        });
+    };
+    let webhooks = conn
+        .query(
+            "select id, table_name, events, filter, url, secret from tankard_webhooks;",
+            &[],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    for (_, (id, change)) in changes {
+        let (Some(table), Some(op)) = (
+            change.get("table").and_then(Value::as_str),
+            change.get("op").and_then(Value::as_str),
+        ) else {
+            continue;
+        };
+        let Some(columns) = tables.get(table) else {
+            continue;
+        };
+        for row in webhooks.iter().filter(|row| {
+            row.get::<_, &str>("table_name") == table
+                && row.get::<_, Vec<&str>>("events").contains(&op)
+        }) {
+            let webhook = Webhook {
+                id: row.get("id"),
+                url: row.get("url"),
+                secret: row.get("secret"),
+            };
+            let filter = row.get::<_, &str>("filter");
+            let feed = match (
+                Select::from_query(filter, columns),
+                Filters::from_query(filter, columns),
+            ) {
+                (Ok(Select(select)), Ok(filters)) => Feed::new(
+                    table.to_string(),
+                    columns.clone(),
+                    &select,
+                    filters,
+                    Context::default(),
+                ),
+                (Err((_, err)), _) | (_, Err((_, err))) => {
+                    let payload = Value::Object(change.clone());
+                    dead_letter(state.pool, &webhook, &payload, &err, 0).await;
+                    continue;
+                }
+            };
+            // filters failing on a change, like a cast of a value, leave the transaction usable
+            conn.batch_execute("savepoint webhook;")
+                .await
+                .map_err(internal_error)?;
+            let captured = feed.captured(&conn, (id, change.clone())).await;
+            conn.batch_execute("rollback to savepoint webhook;")
+                .await
+                .map_err(internal_error)?;
+            if let Some((id, mut payload)) = captured {
+                payload.insert("id".to_string(), id.into());
+                conn.execute(
+                    "select tankard_enqueue($1, $2, max_attempts => $3, key => $4);",
+                    &[
+                        &JOB,
+                        &json!({ "webhook": webhook.id, "payload": payload }),
+                        &ATTEMPTS,
+                        &format!("{JOB}:{}:{id}", webhook.id),
+                    ],
+                )
+                .await
+                .map_err(internal_error)?;
+            }
+        }
+    }
+
+    conn.execute(
+        "update tankard_webhook_cursor set xid = $1::bigint::text::xid8, id = $2;",
+        &[&cursor.xid, &cursor.id],
+    )
+    .await
+    .map_err(internal_error)?;
+    conn.commit().await?;
+    Ok(Dispatched::Some)
+}
+
+/// Dispatch changes to webhooks whenever one is notified. Workers running [`deliver`] take it
+/// from there.
+///
+/// Changes are read from the outbox, the ones committed while no instance ran are delivered too,
+/// with the rows they captured.
+pub(crate) async fn run(
+    mut notifications: Subscription,
+    tables: HashMap<String, Vec<Column>>,
+    state: AppState,
+) {
+    loop {
+        match dispatch(&tables, &state).await {
+            Ok(Dispatched::Some) => continue,
+            Ok(Dispatched::None) => {
+                if notifications.next().await.is_none() {
+                    return;
+                }
+            }
+            // changes held back are not notified again, and failures are tried again
+            Ok(Dispatched::Held) | Err(_) => {
+                tokio::select! {
+                    notification = notifications.next() => {
+                        if notification.is_none() {
+                            return;
+                        }
+                    }
+                    _ = tokio::time::sleep(POLL) => {}
+                }
+            }
+        }
+        while let Some(Some(_)) = notifications.next().now_or_never() {}
+    }
+}
+
+/// Client webhooks are POSTed with.
+pub(crate) fn client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(TIMEOUT)
+        .build()
+        .unwrap_or_default()
+}
+
+/// Job handler POSTing a queued change to its webhook, dead-lettering it on the last attempt.
+pub(crate) async fn deliver(
+    client: reqwest::Client,
+    pool: &'static bb8::Pool<PostgresConnectionManager<NoTls>>,
+    job: Job,
+) -> Result<(), String> {
+    let id = job.payload["webhook"].as_i64().ok_or("no webhook")?;
+    let payload = &job.payload["payload"];
+    let conn = pool.get().await.map_err(|err| err.to_string())?;
+    // webhooks deleted since are not delivered to
+    let Some(row) = conn
+        .query_opt(
+            "select url, secret from tankard_webhooks where id = $1;",
+            &[&id],
+        )
+        .await
+        .map_err(|err| err.to_string())?
+    else {
+        return Ok(());
+    };
+    drop(conn);
+    let webhook = Webhook {
+        id,
+        url: row.get("url"),
+        secret: row.get("secret"),
+    };
+
+    let body = payload.to_string();
+    let result = client
+        .post(&webhook.url)
+        .header(CONTENT_TYPE, "application/json")
+        .header(SIGNATURE, sign(&webhook.secret, &body))
+        .body(body)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status);
+    match result {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            let error = err.to_string();
+            if job.last() {
+                dead_letter(pool, &webhook, payload, &error, job.attempts).await;
+            }
+            Err(error)
+        }
+    }
+}
+
+async fn dead_letter(
+    pool: &'static bb8::Pool<PostgresConnectionManager<NoTls>>,
+    webhook: &Webhook,
+    payload: &Value,
+    error: &str,
+    attempts: i32,
+) {
+    if let Ok(conn) = pool.get().await {
+        _ = conn
+            .execute(
+                "insert into tankard_webhook_dead_letters (webhook, payload, error, attempts) values ($1, $2, $3, $4);",
+                &[&webhook.id, payload, &error, &attempts],
+            )
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, sync::Arc, time::Duration};
+
+    use axum::{
+        body::Bytes,
+        extract::State,
+        http::{HeaderMap, StatusCode},
+        routing::post,
+        Router,
+    };
+    use serde_json::{json, Value};
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    use super::{client, deliver, sign, JOB, SIGNATURE};
+    use crate::{
+        api, app, changes,
+        hub::{Hub, Limits},
+        jobs::{work, Handlers, Job},
+        jwt, rate_limits,
+        tests::{setup_app, setup_pool},
+    };
+
+    /// Serve `status` on a local port, passing on the signature and body of every request.
+    async fn mock(
+        status: StatusCode,
+    ) -> Result<(String, mpsc::UnboundedReceiver<(String, Value)>), Box<dyn Error>> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let app = Router::new()
+            .route(
+                "/",
+                post(
+                    move |State(sender): State<mpsc::UnboundedSender<(String, Value)>>,
+                          headers: HeaderMap,
+                          body: Bytes| async move {
+                        let signature = headers
+                            .get(SIGNATURE)
+                            .and_then(|s| s.to_str().ok())
+                            .unwrap_or_default()
+                            .to_string();
+                        let body = serde_json::from_slice(&body).unwrap_or_default();
+                        _ = sender.send((signature, body));
+                        status
+                    },
+                ),
+            )
+            .with_state(sender);
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/", listener.local_addr()?);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Ok((url, receiver))
+    }
+
+    #[test]
+    fn signature() {
+        assert_eq!(
+            sign("key", "The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[tokio::test]
+    async fn users_webhook() -> Result<(), Box<dyn Error>> {
+        let db_name = "c2e8a5f1-7b3d-4e96-a0c4-5d1f8b2e7a39";
+        let (conn, _) = setup_app(db_name).await?;
+        let (pool, config) = setup_pool(db_name).await?;
+        let (url, mut requests) = mock(StatusCode::OK).await?;
+
+        let client = client();
+        let handlers =
+            Handlers::default().register(JOB, move |job| deliver(client.clone(), pool, job));
+        tokio::spawn(work(
+            pool,
+            Hub::new(config.clone(), Limits::default()),
+            Arc::new(handlers),
+            "default".to_string(),
+        ));
+        // another instance, dispatching the same changes
+        let _other = app(
+            pool,
+            Hub::new(config, Limits::default()),
+            changes::CHANNEL,
+            jwt::Keys::default(),
+            rate_limits::RateLimits::default(),
+            api::ContextConfig::default(),
+        )
+        .await?;
+
+        conn.execute(
+            "insert into tankard_webhooks (table_name, events, filter, url, secret) values ('users', '{insert}', 'select=username&email=eq.foo', $1, 'secret');",
+            &[&url],
+        )
+        .await?;
+        conn.batch_execute(
+            "insert into users (username, salt, passhash, email) values ('one', '', '', 'bar'); insert into users (username, salt, passhash, email) values ('two', '', '', 'foo');",
+        )
+        .await?;
+
+        let (signature, body) = requests.recv().await.unwrap();
+
+        assert_eq!(signature, sign("secret", &body.to_string()));
+        assert_eq!(body["op"], "insert");
+        assert_eq!(body["new"], json!({ "username": "two" }));
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), requests.recv())
+                .await
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_webhook_resumed() -> Result<(), Box<dyn Error>> {
+        let db_name = "5e0b9c27-3f8a-4d61-b2e4-a7c1f6d8094e";
+        let (conn, _) = setup_app(db_name).await?;
+        let (pool, config) = setup_pool(db_name).await?;
+        let (url, mut requests) = mock(StatusCode::OK).await?;
+
+        let client = client();
+        let handlers =
+            Handlers::default().register(JOB, move |job| deliver(client.clone(), pool, job));
+        tokio::spawn(work(
+            pool,
+            Hub::new(config, Limits::default()),
+            Arc::new(handlers),
+            "default".to_string(),
+        ));
+
+        // no instance dispatches until the lock is let go
+        conn.execute(
+            "select pg_advisory_lock(hashtext('tankard_webhook_cursor'));",
+            &[],
+        )
+        .await?;
+        conn.execute(
+            "insert into tankard_webhooks (table_name, filter, url, secret) values ('users', 'select=username', $1, 'secret');",
+            &[&url],
+        )
+        .await?;
+        conn.batch_execute(
+            "insert into users (username, salt, passhash, email) values ('one', '', '', 'bar'); update users set username = 'two'; delete from users;",
+        )
+        .await?;
+        conn.execute(
+            "select pg_advisory_unlock(hashtext('tankard_webhook_cursor'));",
+            &[],
+        )
+        .await?;
+
+        let mut bodies = Vec::new();
+        for _ in 0..3 {
+            bodies.push(requests.recv().await.unwrap().1);
+        }
+        bodies.sort_by_key(|body| body["id"].as_i64());
+
+        // the rows as they were changed, not as they are now
+        assert_eq!(
+            bodies
+                .iter()
+                .map(|body| (body["op"].clone(), body["old"].clone(), body["new"].clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (json!("insert"), Value::Null, json!({ "username": "one" })),
+                (
+                    json!("update"),
+                    json!({ "username": "one" }),
+                    json!({ "username": "two" })
+                ),
+                (json!("delete"), json!({ "username": "two" }), Value::Null),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn webhook_dead_letter() -> Result<(), Box<dyn Error>> {
+        let db_name = "f1b7d3a9-5c2e-4f80-b6a1-9e4c0d7f2b58";
+        let (conn, _) = setup_app(db_name).await?;
+        let (url, mut requests) = mock(StatusCode::INTERNAL_SERVER_ERROR).await?;
+
+        let id = conn
+            .query_one(
+                "insert into tankard_webhooks (table_name, url, secret) values ('users', $1, 'secret') returning id;",
+                &[&url],
+            )
+            .await?
+            .get::<_, i64>(0);
+        let (pool, _) = setup_pool(db_name).await?;
+        let job = |attempts| Job {
+            payload: json!({ "webhook": id, "payload": { "op": "insert" } }),
+            attempts,
+            max_attempts: 3,
+        };
+
+        for attempts in 1..=3 {
+            assert!(deliver(client(), pool, job(attempts)).await.is_err());
+            assert!(requests.recv().await.is_some());
+        }
+        assert_eq!(
+            conn.query(
+                "select attempts, payload from tankard_webhook_dead_letters where webhook = $1;",
+                &[&id],
+            )
+            .await?
+            .iter()
+            .map(|row| (row.get::<_, i32>(0), row.get::<_, Value>(1)))
+            .collect::<Vec<_>>(),
+            vec![(3, json!({ "op": "insert" }))]
+        );
+
+        Ok(())
+    }
+}
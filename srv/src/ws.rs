@@ -0,0 +1,514 @@
+use std::{
+    collections::HashMap,
+    future,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        State, WebSocketUpgrade,
+    },
+    response::Response,
+    Extension,
+};
+use futures::{stream::SplitSink, SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::{
+    sync::{mpsc, Notify},
+    task::JoinHandle,
+    time::{interval_at, sleep_until, Instant},
+};
+use tokio_postgres::types::Type;
+
+use crate::{
+    api::{merge, Column, Context, Filters, Select, Transaction},
+    auth::Session,
+    changes::{change, Feed},
+    AppState,
+};
+
+/// Replies buffered for a client, which is disconnected when it falls further behind.
+const REPLIES: usize = 256;
+/// How often a socket opened with a session checks that it was not ended, besides on every
+/// message of the client.
+const SESSION_CHECK: Duration = Duration::from_secs(30);
+
+/// Messages a client sends over `/ws`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Request {
+    /// Subscribe to the changes of `table`, `filter` is a query string like `/api/:table` takes.
+    Subscribe {
+        id: String,
+        table: String,
+        #[serde(default)]
+        filter: String,
+    },
+    Unsubscribe {
+        id: String,
+    },
+    /// Merge `rows` into `table`, like posting them to `/api/:table`.
+    Write {
+        id: String,
+        table: String,
+        rows: Value,
+    },
+}
+
+/// Messages the server sends over `/ws`, `id` refers to the request they answer.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Reply {
+    Ack {
+        id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        rows: Option<Vec<Value>>,
+    },
+    Error {
+        id: Option<String>,
+        message: String,
+    },
+    Change {
+        id: String,
+        seq: i64,
+        #[serde(flatten)]
+        change: Map<String, Value>,
+    },
+}
+
+pub(crate) async fn ws(
+    upgrade: WebSocketUpgrade,
+    Extension(tables): Extension<HashMap<String, Vec<Column>>>,
+    State(state): State<AppState>,
+    context: Context,
+) -> Response {
+    upgrade.on_upgrade(move |socket| session(socket, tables, state, context))
+}
+
+/// Replies to a client, closing the socket rather than buffering without end when it does not
+/// keep up, like subscribers of the hub with [`Lagged::Disconnect`](crate::hub::Lagged).
+#[derive(Clone)]
+struct Replies {
+    sender: mpsc::Sender<Reply>,
+    lagged: Arc<Notify>,
+}
+
+impl Replies {
+    /// `false` once the client lagged or went away.
+    fn send(&self, reply: Reply) -> bool {
+        match self.sender.try_send(reply) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.lagged.notify_one();
+                false
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+/// `false` once the session the socket was opened with ended, by logout or expiry.
+///
+/// Errors reading it count as signed in, so an outage of the database does not close every socket.
+async fn signed_in(state: &AppState, context: &Context) -> bool {
+    let (Some(Session(token)), Some(user)) = (&context.session, &context.user) else {
+        return true;
+    };
+    let Ok(conn) = state.pool.get().await else {
+        return true;
+    };
+    conn.query_one(
+        "select tankard_session_user($1)::text = $2;",
+        &[token, &user.id],
+    )
+    .await
+    .map_or(true, |row| row.get::<_, Option<bool>>(0) == Some(true))
+}
+
+async fn close(sink: &mut SplitSink<WebSocket, Message>, code: u16, reason: &'static str) {
+    _ = sink
+        .send(Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        })))
+        .await;
+}
+
+/// Writes run in the [`Context`] of the upgrade request. The token or session it was signed in
+/// with is not checked again by requests, so the socket closes once it expires or ends.
+async fn session(
+    socket: WebSocket,
+    tables: HashMap<String, Vec<Column>>,
+    state: AppState,
+    context: Context,
+) {
+    let (mut sink, mut stream) = socket.split();
+    let (sender, mut outgoing) = mpsc::channel(REPLIES);
+    let replies = Replies {
+        sender,
+        lagged: Arc::default(),
+    };
+    let mut subscriptions = HashMap::new();
+    let expires = context.claims.expires().map(|exp| {
+        let left = exp - chrono::Utc::now().timestamp();
+        Instant::now() + Duration::from_secs(left.max(0) as u64)
+    });
+    let expired = async move {
+        match expires {
+            Some(at) => sleep_until(at).await,
+            None => future::pending().await,
+        }
+    };
+    tokio::pin!(expired);
+    let mut check = interval_at(Instant::now() + SESSION_CHECK, SESSION_CHECK);
+
+    loop {
+        tokio::select! {
+            _ = replies.lagged.notified() => {
+                state.hub.metrics().disconnected.fetch_add(1, Ordering::Relaxed);
+                close(&mut sink, close_code::AGAIN, "too many replies pending").await;
+                break;
+            }
+            _ = &mut expired => {
+                close(&mut sink, close_code::POLICY, "token expired").await;
+                break;
+            }
+            _ = check.tick(), if context.session.is_some() => {
+                if !signed_in(&state, &context).await {
+                    close(&mut sink, close_code::POLICY, "session ended").await;
+                    break;
+                }
+            }
+            Some(reply) = outgoing.recv() => {
+                let Ok(text) = serde_json::to_string(&reply) else {
+                    continue;
+                };
+                if sink.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+            message = stream.next() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    if !signed_in(&state, &context).await {
+                        close(&mut sink, close_code::POLICY, "session ended").await;
+                        break;
+                    }
+                    let reply = match serde_json::from_str(&text) {
+                        Ok(request) => {
+                            handle(request, &tables, &state, &context, &replies, &mut subscriptions).await
+                        }
+                        Err(err) => Reply::Error {
+                            id: None,
+                            message: err.to_string(),
+                        },
+                    };
+                    replies.send(reply);
+                }
+                Some(Ok(_)) => {}
+                Some(Err(_)) | None => break,
+            },
+        }
+    }
+
+    for task in subscriptions.into_values() {
+        task.abort();
+    }
+}
+
+async fn handle(
+    request: Request,
+    tables: &HashMap<String, Vec<Column>>,
+    state: &AppState,
+    context: &Context,
+    replies: &Replies,
+    subscriptions: &mut HashMap<String, JoinHandle<()>>,
+) -> Reply {
+    let columns = |table: &str| {
+        tables
+            .get(table)
+            .cloned()
+            .ok_or_else(|| format!("{table} not found"))
+    };
+
+    let result = match request {
+        Request::Subscribe { id, table, filter } => {
+            let subscribed = async {
+                if subscriptions.contains_key(&id) {
+                    return Err(format!("{id} is already subscribed"));
+                }
+                if let Some(key) = &context.api_key {
+                    key.authorize("read", &table).map_err(|(_, e)| e)?;
+                }
+                let columns = columns(&table)?;
+                let Select(select) = Select::from_query(&filter, &columns).map_err(|(_, e)| e)?;
+                let filters = Filters::from_query(&filter, &columns).map_err(|(_, e)| e)?;
+                let conn = Transaction::begin(state.pool, context)
+                    .await
+                    .map_err(|(_, e)| e)?;
+                let select = state
+                    .privileges
+                    .authorize(&conn, &table, &columns, select, &filters)
+                    .await
+                    .map_err(|(_, e)| e)?;
+                drop(conn);
+                let feed = Arc::new(Feed::new(table, columns, &select, filters, context.clone()));
+
+                let mut notifications = state
+                    .hub
+                    .subscribe(state.changes)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                let (state, replies, sub) = (state.clone(), replies.clone(), id.clone());
+                let task = tokio::spawn(async move {
+                    while let Some(n) = notifications.next().await {
+                        let Some(change) = change(&n.payload) else {
+                            continue;
+                        };
+                        if let Some((seq, change)) = feed.change(&state, change).await {
+                            let id = sub.clone();
+                            if !replies.send(Reply::Change { id, seq, change }) {
+                                break;
+                            }
+                        }
+                    }
+                });
+                subscriptions.insert(id.clone(), task);
+                Ok(None)
+            }
+            .await;
+            (id, subscribed)
+        }
+        Request::Unsubscribe { id } => {
+            let unsubscribed = subscriptions
+                .remove(&id)
+                .map(|task| {
+                    task.abort();
+                    None
+                })
+                .ok_or_else(|| format!("{id} is not subscribed"));
+            (id, unsubscribed)
+        }
+        Request::Write { id, table, rows } => {
+            let written = async {
+                if let Some(key) = &context.api_key {
+                    key.authorize("write", &table).map_err(|(_, e)| e)?;
+                }
+                let columns = columns(&table)?;
+                let conn = Transaction::begin(state.pool, context)
+                    .await
+                    .map_err(|(_, e)| e)?;
+                // the merged rows are returned as far as the role may see them, like `set_table`
+                let select = state
+                    .privileges
+                    .authorize(&conn, &table, &columns, vec![], &Filters::default())
+                    .await
+                    .map_err(|(_, e)| e)?;
+                let rows = conn
+                    .query_typed(&merge(&table, &columns, &select), &[(&rows, Type::JSONB)])
+                    .await
+                    .map_err(|e| e.to_string())?;
+                conn.commit().await.map_err(|(_, e)| e)?;
+                Ok(Some(rows.into_iter().map(|row| row.get(0)).collect()))
+            }
+            .await;
+            (id, written)
+        }
+    };
+
+    match result {
+        (id, Ok(rows)) => Reply::Ack { id, rows },
+        (id, Err(message)) => Reply::Error {
+            id: Some(id),
+            message,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, time::Duration};
+
+    use axum::http::header::{AUTHORIZATION, COOKIE};
+    use futures::{SinkExt, Stream, StreamExt};
+    use serde_json::{json, Value};
+    use tokio::{net::TcpListener, time::timeout};
+    use tokio_tungstenite::{
+        connect_async,
+        tungstenite::{self, client::IntoClientRequest, Message},
+    };
+
+    use crate::{jwt, tests::setup_app};
+
+    async fn reply(
+        socket: &mut (impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin),
+    ) -> Result<Value, Box<dyn Error>> {
+        match socket.next().await {
+            Some(Ok(Message::Text(text))) => Ok(serde_json::from_str(&text)?),
+            message => Err(format!("unexpected {message:?}").into()),
+        }
+    }
+
+    #[tokio::test]
+    async fn users_ws() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("8d2f6a1c-4e7b-4c93-b5a8-1f0e9d3c7b26").await?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let (mut socket, _) = connect_async(format!("ws://{addr}/ws")).await?;
+
+        socket
+            .send(Message::Text(
+                json!({ "type": "subscribe", "id": "foo", "table": "users", "filter": "select=username&email=eq.foo" })
+                    .to_string(),
+            ))
+            .await?;
+        assert_eq!(
+            reply(&mut socket).await?,
+            json!({ "type": "ack", "id": "foo" })
+        );
+
+        socket
+            .send(Message::Text(
+                json!({ "type": "subscribe", "id": "bar", "table": "nope" }).to_string(),
+            ))
+            .await?;
+        assert_eq!(
+            reply(&mut socket).await?,
+            json!({ "type": "error", "id": "bar", "message": "nope not found" })
+        );
+
+        conn.batch_execute(
+            "insert into users (username, salt, passhash, email) values ('one', '', '', 'bar'); insert into users (username, salt, passhash, email) values ('two', '', '', 'foo');",
+        )
+        .await?;
+
+        let change = reply(&mut socket).await?;
+        assert_eq!(change["type"], "change");
+        assert_eq!(change["id"], "foo");
+        assert_eq!(change["op"], "insert");
+        assert_eq!(change["new"], json!({ "username": "two" }));
+
+        socket
+            .send(Message::Text(
+                json!({ "type": "unsubscribe", "id": "foo" }).to_string(),
+            ))
+            .await?;
+        assert_eq!(
+            reply(&mut socket).await?,
+            json!({ "type": "ack", "id": "foo" })
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_ws_write() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("0c6e9b3f-5a2d-4f71-8e4b-d9a1c7f2e508").await?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let (mut socket, _) = connect_async(format!("ws://{addr}/ws")).await?;
+
+        socket
+            .send(Message::Text(
+                json!({ "type": "write", "id": "foo", "table": "users", "rows": [{ "username": "one", "salt": "", "passhash": "secret" }] })
+                    .to_string(),
+            ))
+            .await?;
+        let ack = reply(&mut socket).await?;
+
+        assert_eq!(ack["type"], "ack");
+        assert_eq!(ack["id"], "foo");
+        assert_eq!(ack["rows"][0]["username"], "one");
+        assert!(ack["rows"][0].get("passhash").is_none());
+        assert_eq!(
+            conn.query_one("select passhash from users;", &[])
+                .await?
+                .get::<_, String>(0),
+            "secret"
+        );
+
+        socket
+            .send(Message::Text(
+                json!({ "type": "write", "id": "bar", "table": "nope", "rows": [] }).to_string(),
+            ))
+            .await?;
+        assert_eq!(
+            reply(&mut socket).await?,
+            json!({ "type": "error", "id": "bar", "message": "nope not found" })
+        );
+
+        Ok(())
+    }
+    /// The reason of the close frame the server sends next.
+    async fn closed(
+        socket: &mut (impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin),
+    ) -> Result<String, Box<dyn Error>> {
+        match timeout(Duration::from_secs(5), socket.next()).await? {
+            Some(Ok(Message::Close(Some(frame)))) => Ok(frame.reason.to_string()),
+            message => Err(format!("unexpected {message:?}").into()),
+        }
+    }
+
+    #[tokio::test]
+    async fn users_ws_expired() -> Result<(), Box<dyn Error>> {
+        let (_conn, app) = setup_app("4f9c2e7a-1d3b-4a65-8e0f-7b2d5c9a1e36").await?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let token = jwt::tests::token(json!({ "exp": chrono::Utc::now().timestamp() + 1 }));
+        let mut request = format!("ws://{addr}/ws").into_client_request()?;
+        request
+            .headers_mut()
+            .insert(AUTHORIZATION, format!("Bearer {token}").parse()?);
+        let (mut socket, _) = connect_async(request).await?;
+
+        assert_eq!(closed(&mut socket).await?, "token expired");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn users_ws_session() -> Result<(), Box<dyn Error>> {
+        let (conn, app) = setup_app("a6e1d8b3-5c2f-4e97-9b4a-2f8c7d1e0a59").await?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let token = conn
+            .query_one(
+                "with one as (insert into users (username, salt, passhash) values ('one', '', '') returning id) select tankard_session_start(id) from one;",
+                &[],
+            )
+            .await?
+            .get::<_, String>(0);
+        let mut request = format!("ws://{addr}/ws").into_client_request()?;
+        request
+            .headers_mut()
+            .insert(COOKIE, format!("tankard_session={token}").parse()?);
+        let (mut socket, _) = connect_async(request).await?;
+
+        let subscribe = |id: &str| {
+            Message::Text(json!({ "type": "subscribe", "id": id, "table": "users" }).to_string())
+        };
+        socket.send(subscribe("foo")).await?;
+        assert_eq!(
+            reply(&mut socket).await?,
+            json!({ "type": "ack", "id": "foo" })
+        );
+
+        conn.execute("select tankard_session_end($1);", &[&token])
+            .await?;
+        socket.send(subscribe("bar")).await?;
+
+        assert_eq!(closed(&mut socket).await?, "session ended");
+
+        Ok(())
+    }
+}